@@ -0,0 +1,192 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use mlua::{Lua, Table, Value};
+
+use crate::logger::log;
+
+/// Something wrong with the config that doesn't stop it from loading, like two keymaps bound to
+/// the same keys.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Where in the config the problem is, as "file:line".
+    pub location: String,
+    pub message: String,
+}
+
+/// Problems found while loading the config. The daemon logs them right away, `crusty-keys check`
+/// lists them once the config is loaded.
+#[derive(Default, Clone)]
+pub struct Problems {
+    problems: Arc<RwLock<Vec<Problem>>>,
+    /// Set by `crusty-keys check` and `crusty-keys inspect`, commands are only checked instead of
+    /// being run.
+    checking: bool,
+}
+
+impl Problems {
+    pub fn new(checking: bool) -> Self {
+        Problems {
+            problems: Arc::default(),
+            checking,
+        }
+    }
+
+    pub fn checking(&self) -> bool {
+        self.checking
+    }
+
+    pub fn report(&self, location: String, message: String) {
+        if !self.checking {
+            log(format!("{location}: {message}"));
+        }
+        if let Ok(mut problems) = self.problems.write() {
+            problems.push(Problem { location, message });
+        }
+    }
+
+    /// Reports the keys of an options table that are not one of `known`, mostly typos.
+    pub fn check_options(&self, lua: &Lua, options: Option<&Table>, known: &[&str]) {
+        let Some(options) = options else {
+            return;
+        };
+
+        options
+            .for_each(|key: Value, _: Value| {
+                let key = key.to_string()?;
+                if !known.contains(&key.as_str()) {
+                    self.report(
+                        location(lua),
+                        format!(
+                            "unknown option \"{key}\", expected one of {}",
+                            known.join(", ")
+                        ),
+                    );
+                }
+                Ok(())
+            })
+            .unwrap_or_default();
+    }
+
+    /// Reports a command line whose executable is not found.
+    pub fn check_command(&self, lua: &Lua, args: &str) {
+        match args.split_whitespace().next() {
+            Some(cmd) if !is_executable(cmd) => {
+                self.report(location(lua), format!("executable \"{cmd}\" not found"))
+            }
+            None => self.report(location(lua), "empty command".to_string()),
+            _ => {}
+        }
+    }
+
+    /// Prints all problems, returns whether there were any.
+    pub fn print(&self) -> bool {
+        let Ok(problems) = self.problems.read() else {
+            return false;
+        };
+        problems
+            .iter()
+            .for_each(|problem| println!("{}: {}", problem.location, problem.message));
+
+        match problems.len() {
+            0 => println!("No problems found"),
+            1 => println!("1 problem found"),
+            n => println!("{n} problems found"),
+        }
+        !problems.is_empty()
+    }
+}
+
+/// The config file and line the running Rust function was called from, as "file:line".
+pub fn location(lua: &Lua) -> String {
+    lua.inspect_stack(1, |debug| {
+        let source = debug.source();
+        let file = source.short_src.unwrap_or_default();
+        match debug.current_line() {
+            Some(line) => format!("{file}:{line}"),
+            None => file.to_string(),
+        }
+    })
+    .unwrap_or_else(|| "?".to_string())
+}
+
+/// Whether `cmd` is an executable file, commands without a slash are looked up in `$PATH`.
+fn is_executable(cmd: &str) -> bool {
+    let executable = |path: &Path| {
+        path.metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+
+    if cmd.contains('/') {
+        return executable(Path::new(cmd));
+    }
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| executable(&dir.join(cmd))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(problems: &Problems) -> Vec<String> {
+        problems
+            .problems
+            .read()
+            .map(|problems| problems.iter().map(|p| p.message.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn check_options_reports_unknown_keys() -> mlua::Result<()> {
+        let lua = Lua::new();
+        let problems = Problems::new(true);
+        let options: Table = lua
+            .load(r#"{ desc = "", gruop = "", [1] = true }"#)
+            .eval()?;
+        problems.check_options(&lua, Some(&options), &["group", "desc"]);
+
+        let mut reported = messages(&problems);
+        reported.sort();
+        assert_eq!(
+            reported,
+            [
+                "unknown option \"1\", expected one of group, desc",
+                "unknown option \"gruop\", expected one of group, desc",
+            ]
+        );
+
+        let problems = Problems::new(true);
+        problems.check_options(&lua, None, &["group"]);
+        assert!(messages(&problems).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn check_command_reports_missing_executables() {
+        let lua = Lua::new();
+        let problems = Problems::new(true);
+        problems.check_command(&lua, "sh -c true");
+        problems.check_command(&lua, "crusty-keys-no-such-command --flag");
+        problems.check_command(&lua, "  ");
+        assert_eq!(
+            messages(&problems),
+            [
+                "executable \"crusty-keys-no-such-command\" not found",
+                "empty command",
+            ]
+        );
+    }
+
+    #[test]
+    fn executables_are_looked_up_in_path() {
+        assert!(is_executable("/bin/sh"));
+        assert!(is_executable("sh"));
+        assert!(!is_executable("/etc/passwd"));
+        assert!(!is_executable("/bin"));
+        assert!(!is_executable("/no/such/file"));
+        assert!(!is_executable("crusty-keys-no-such-command"));
+    }
+}
@@ -1,13 +1,15 @@
 use std::{fs::read_to_string, path::PathBuf};
 
 use crate::{
-    AppArgs,
+    AppArgs, Mode,
     error::{AppError, AppResult},
 };
 
 pub(crate) struct Config {
     pub(crate) cfg: String,
     pub(crate) dir: String,
+    /// Path of the config file, used as chunk name so errors and problems point into it.
+    pub(crate) path: PathBuf,
 }
 
 impl TryFrom<&AppArgs> for Config {
@@ -22,9 +24,12 @@ impl TryFrom<&AppArgs> for Config {
             return Ok(Config {
                 cfg: config,
                 dir: path.to_string(),
+                path: valid_config_path.clone(),
             });
         }
-        eprintln!("Invalid config path... Using default");
+        if value.config.is_some() && !matches!(value.mode, Mode::Check) {
+            eprintln!("Invalid config path... Using default");
+        }
         Err(())
     }
 }
@@ -54,6 +59,7 @@ impl Config {
                 .to_str()
                 .map(|s| s.to_string())
                 .ok_or(AppError::ConfigCouldNotBeCreated)?,
+            path: config_file_path,
         })
     }
 }
@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::PathBuf};
 
 use x11_dl::error::OpenError;
 
@@ -7,12 +7,16 @@ pub type AppResult<T> = std::result::Result<T, AppError>;
 #[derive(Debug)]
 pub enum AppError {
     XlibOpen(OpenError),
+    DisplayNotFound,
     IO(std::io::Error),
     Lua(mlua::Error),
     ConfigCouldNotBeCreated,
+    InvalidConfig(PathBuf),
     HomeEnvNotSet,
     ReadLockError,
-    _WriteLockError,
+    WriteLockError,
+    AlreadyRunning(Option<i32>),
+    ReplaceTimeout(i32),
 }
 
 impl std::error::Error for AppError {}
@@ -25,14 +29,28 @@ impl Display for AppError {
                 e.kind(),
                 e.detail()
             )),
+            AppError::DisplayNotFound => f.write_str("Could not open X display, is $DISPLAY set?"),
             AppError::IO(error) => f.write_fmt(format_args!("IO Error: {error}")),
             AppError::Lua(error) => f.write_fmt(format_args!("Lua Error: {error}")),
             AppError::ConfigCouldNotBeCreated => f.write_str("Unable to create config file."),
+            AppError::InvalidConfig(path) => f.write_fmt(format_args!(
+                "Could not read the config file \"{}\"",
+                path.display()
+            )),
             AppError::HomeEnvNotSet => {
                 f.write_str("Home env variable not set. Could not determen config location")
             }
             AppError::ReadLockError => f.write_str("Could not get RLock"),
-            AppError::_WriteLockError => f.write_str("Could not get WLock"),
+            AppError::WriteLockError => f.write_str("Could not get WLock"),
+            AppError::AlreadyRunning(Some(pid)) => f.write_fmt(format_args!(
+                "crusty-keys is already running on this display (pid {pid}). Use `daemon --replace` to take over"
+            )),
+            AppError::AlreadyRunning(None) => f.write_str(
+                "crusty-keys is already running on this display. Use `daemon --replace` to take over",
+            ),
+            AppError::ReplaceTimeout(pid) => f.write_fmt(format_args!(
+                "Running instance (pid {pid}) did not exit in time"
+            )),
         }
     }
 }
@@ -0,0 +1,112 @@
+use std::{
+    io,
+    os::fd::RawFd,
+    time::{Duration, Instant},
+};
+
+use libc::c_int;
+
+use crate::{logger::log, signals::Shutdown};
+
+/// Something the event loop waits on.
+///
+/// A source can be driven by a file descriptor, by a deadline or by both. All sources are
+/// dispatched from the thread running the loop, so they are free to call into Lua.
+pub trait EventSource {
+    /// File descriptor that is polled for readability.
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Point in time at which the source wants to be dispatched, even without any fd activity.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Whether the source already buffered events that won't show up on its fd anymore.
+    fn pending(&mut self) -> bool {
+        false
+    }
+
+    /// Handle whatever woke the source up, returning `Some` stops the loop.
+    fn dispatch(&mut self) -> Option<Shutdown>;
+}
+
+#[derive(Default)]
+pub struct EventLoop<'a> {
+    sources: Vec<Box<dyn EventSource + 'a>>,
+}
+
+impl<'a> EventLoop<'a> {
+    pub fn register<S: EventSource + 'a>(&mut self, source: S) {
+        self.sources.push(Box::new(source));
+    }
+
+    pub fn run(&mut self) -> Shutdown {
+        loop {
+            for source in self.sources.iter_mut() {
+                if source.pending()
+                    && let Some(shutdown) = source.dispatch()
+                {
+                    return shutdown;
+                }
+            }
+
+            // fds can change between iterations (e.g. on reconnects), so the set is rebuild
+            // every time.
+            let (mut fds, owners): (Vec<libc::pollfd>, Vec<usize>) = self
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(i, source)| {
+                    source.fd().map(|fd| {
+                        let pfd = libc::pollfd {
+                            fd,
+                            events: libc::POLLIN,
+                            revents: 0,
+                        };
+                        (pfd, i)
+                    })
+                })
+                .unzip();
+
+            let timeout = self
+                .sources
+                .iter()
+                .filter_map(|source| source.deadline())
+                .min()
+                .map(|deadline| poll_timeout(deadline.saturating_duration_since(Instant::now())))
+                .unwrap_or(-1);
+
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    log(format!("Poll error: {err}"));
+                    return Shutdown::Exit(1);
+                }
+                continue;
+            }
+
+            let now = Instant::now();
+            let mut ready = vec![false; self.sources.len()];
+            fds.iter()
+                .zip(owners)
+                .filter(|(pfd, _)| pfd.revents != 0)
+                .for_each(|(_, i)| ready[i] = true);
+
+            for (i, source) in self.sources.iter_mut().enumerate() {
+                let expired = source.deadline().is_some_and(|deadline| deadline <= now);
+                if (ready[i] || expired)
+                    && let Some(shutdown) = source.dispatch()
+                {
+                    return shutdown;
+                }
+            }
+        }
+    }
+}
+
+/// Converts to milliseconds rounding up, so we never wake up just before a deadline and spin.
+fn poll_timeout(duration: Duration) -> c_int {
+    duration.as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int
+}
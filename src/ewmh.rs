@@ -0,0 +1,140 @@
+use std::ffi::c_long;
+
+use mlua::{IntoLua, Lua};
+use x11_dl::xlib::Window;
+
+use crate::xconn::{Display, Geometry};
+
+// Requests to the window manager following the EWMH spec, these work under any compliant window
+// manager: https://specifications.freedesktop.org/wm-spec/latest/
+
+/// Source indication telling the window manager the request comes from a pager like tool, those
+/// are not subject to focus stealing prevention.
+const SOURCE_PAGER: c_long = 2;
+
+const STATE_TOGGLE: c_long = 2;
+
+/// A top level window managed by the window manager.
+pub struct Client {
+    pub id: Window,
+    pub title: Option<String>,
+    pub class: Option<String>,
+    pub instance: Option<String>,
+    pub desktop: Option<u64>,
+    pub pid: Option<u64>,
+    pub geometry: Option<Geometry>,
+}
+
+impl Client {
+    pub fn new(display: &Display, id: Window) -> Self {
+        // WM_CLASS holds the instance followed by the class
+        let mut wm_class = display.get_strings(id, "WM_CLASS").into_iter();
+        let instance = wm_class.next();
+        let class = wm_class.next();
+
+        Self {
+            id,
+            title: display
+                .get_string(id, "_NET_WM_NAME")
+                .or_else(|| display.get_string(id, "WM_NAME")),
+            class,
+            instance,
+            desktop: display.get_long(id, "_NET_WM_DESKTOP"),
+            pid: display.get_long(id, "_NET_WM_PID"),
+            geometry: display.geometry(id),
+        }
+    }
+}
+
+impl IntoLua for Client {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("id", self.id)?;
+        table.set("title", self.title)?;
+        table.set("class", self.class)?;
+        table.set("instance", self.instance)?;
+        table.set("desktop", self.desktop)?;
+        table.set("pid", self.pid)?;
+        table.set("geometry", self.geometry)?;
+        table.into_lua(lua)
+    }
+}
+
+pub fn active_window(display: &Display) -> Option<Window> {
+    display
+        .get_long(display.root, "_NET_ACTIVE_WINDOW")
+        .filter(|w| *w != 0)
+}
+
+/// Managed windows in the order they were mapped.
+pub fn client_list(display: &Display) -> Vec<Window> {
+    display.get_longs(display.root, "_NET_CLIENT_LIST")
+}
+
+pub fn current_desktop(display: &Display) -> Option<u64> {
+    display.get_long(display.root, "_NET_CURRENT_DESKTOP")
+}
+
+/// Names of all desktops, desktops without a name are returned as their number.
+pub fn desktops(display: &Display) -> Vec<String> {
+    let count = display
+        .get_long(display.root, "_NET_NUMBER_OF_DESKTOPS")
+        .unwrap_or_default();
+    let mut names = display
+        .get_strings(display.root, "_NET_DESKTOP_NAMES")
+        .into_iter();
+
+    (0..count)
+        .map(|i| names.next().unwrap_or_else(|| i.to_string()))
+        .collect()
+}
+
+pub fn activate(display: &Display, window: Window) {
+    display.send_client_message(window, "_NET_ACTIVE_WINDOW", [SOURCE_PAGER, 0, 0, 0, 0]);
+}
+
+pub fn close(display: &Display, window: Window) {
+    display.send_client_message(window, "_NET_CLOSE_WINDOW", [0, SOURCE_PAGER, 0, 0, 0]);
+}
+
+pub fn switch_desktop(display: &Display, desktop: u64) {
+    display.send_client_message(
+        display.root,
+        "_NET_CURRENT_DESKTOP",
+        [desktop as c_long, 0, 0, 0, 0],
+    );
+}
+
+pub fn move_to_desktop(display: &Display, window: Window, desktop: u64) {
+    display.send_client_message(
+        window,
+        "_NET_WM_DESKTOP",
+        [desktop as c_long, SOURCE_PAGER, 0, 0, 0],
+    );
+}
+
+/// Toggles a `_NET_WM_STATE_*` state like "FULLSCREEN" or "ABOVE".
+pub fn toggle_state(display: &Display, window: Window, state: &str) {
+    let state = display.atom(&format!("_NET_WM_STATE_{state}")) as c_long;
+    display.send_client_message(
+        window,
+        "_NET_WM_STATE",
+        [STATE_TOGGLE, state, 0, SOURCE_PAGER, 0],
+    );
+}
+
+pub fn move_resize(display: &Display, window: Window, geometry: Geometry) {
+    // Default gravity with the flags telling that x, y, width and height are all set
+    let flags = (0b1111 << 8) | (SOURCE_PAGER << 12);
+    display.send_client_message(
+        window,
+        "_NET_MOVERESIZE_WINDOW",
+        [
+            flags,
+            geometry.x as c_long,
+            geometry.y as c_long,
+            geometry.width as c_long,
+            geometry.height as c_long,
+        ],
+    );
+}
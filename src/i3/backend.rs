@@ -0,0 +1,243 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::Value;
+use x11_dl::xlib;
+
+use crate::{error::AppError, i3::GET_TREE, settings::Settings};
+
+/// A window manager that speaks the i3 IPC protocol.
+///
+/// The wire format is shared, but finding the socket and some reply fields differ between
+/// implementations, those differences are handled here so the `ck.i3` API stays the same.
+pub trait IpcBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Path of the IPC socket of the running window manager.
+    fn socket_path(&self) -> io::Result<PathBuf>;
+
+    /// Whether the window manager emits `event`.
+    fn supports_event(&self, event: &str) -> bool;
+
+    /// Brings a reply to `message_type` into the shape i3 uses.
+    fn normalize(&self, _message_type: u32, _reply: &mut Value) {}
+}
+
+pub struct I3 {
+    socket: Option<PathBuf>,
+    /// The socket path read from the root window, kept while the socket exists.
+    x_socket: Mutex<Option<PathBuf>>,
+}
+
+impl IpcBackend for I3 {
+    fn name(&self) -> &'static str {
+        "i3"
+    }
+
+    fn socket_path(&self) -> io::Result<PathBuf> {
+        if let Some(path) = self.socket.clone().or_else(|| env_path("I3SOCK")) {
+            return Ok(path);
+        }
+        let mut x_socket = self
+            .x_socket
+            .lock()
+            .map_err(|_| io::Error::other(AppError::WriteLockError.to_string()))?;
+        if let Some(path) = x_socket.as_ref().filter(|path| path.exists()) {
+            return Ok(path.clone());
+        }
+        *x_socket = socket_path_from_x();
+        x_socket.clone().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "I3SOCK is not set and the root window has no I3_SOCKET_PATH",
+            )
+        })
+    }
+
+    fn supports_event(&self, event: &str) -> bool {
+        !SWAY_EVENTS.contains(&event)
+    }
+}
+
+/// Events only sway emits.
+const SWAY_EVENTS: [&str; 2] = ["bar_state_update", "input"];
+
+pub struct Sway {
+    socket: Option<PathBuf>,
+}
+
+impl IpcBackend for Sway {
+    fn name(&self) -> &'static str {
+        "sway"
+    }
+
+    fn socket_path(&self) -> io::Result<PathBuf> {
+        self.socket
+            .clone()
+            .or_else(|| env_path("SWAYSOCK"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "SWAYSOCK is not set"))
+    }
+
+    fn supports_event(&self, _event: &str) -> bool {
+        true
+    }
+
+    fn normalize(&self, message_type: u32, reply: &mut Value) {
+        if message_type == GET_TREE {
+            add_window_properties(reply);
+        }
+    }
+}
+
+/// Native wayland windows have an `app_id` instead of X11 `window_properties`, we fill those in
+/// so scripts matching on the class keep working.
+fn add_window_properties(node: &mut Value) {
+    if let Some(app_id) = node.get("app_id").and_then(Value::as_str).map(str::to_string)
+        && node.get("window_properties").is_none_or(Value::is_null)
+    {
+        let title = node.get("name").cloned().unwrap_or(Value::Null);
+        node["window_properties"] = serde_json::json!({
+            "class": app_id,
+            "instance": app_id,
+            "title": title,
+        });
+    }
+
+    for key in ["nodes", "floating_nodes"] {
+        if let Some(children) = node.get_mut(key).and_then(Value::as_array_mut) {
+            children.iter_mut().for_each(add_window_properties);
+        }
+    }
+}
+
+/// Picks the backend from `ck.settings.ipc`, falling back to whichever socket variable is set.
+pub fn select_backend(settings: &Settings) -> Arc<dyn IpcBackend> {
+    let socket = settings.ipc_socket.clone();
+    if is_sway(settings.ipc.as_deref(), env_path) {
+        Arc::new(Sway { socket })
+    } else {
+        Arc::new(I3 {
+            socket,
+            x_socket: Mutex::default(),
+        })
+    }
+}
+
+/// sway sets I3SOCK as well for i3 tools, but i3 never sets SWAYSOCK.
+fn is_sway(ipc: Option<&str>, env: impl Fn(&str) -> Option<PathBuf>) -> bool {
+    match ipc {
+        Some("sway") => true,
+        Some("i3") => false,
+        _ => env("SWAYSOCK").is_some(),
+    }
+}
+
+fn env_path(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+/// i3 publishes its socket path in the `I3_SOCKET_PATH` property of the root window.
+fn socket_path_from_x() -> Option<PathBuf> {
+    let xlib = xlib::Xlib::open().ok()?;
+
+    unsafe {
+        let display = (xlib.XOpenDisplay)(std::ptr::null());
+        if display.is_null() {
+            return None;
+        }
+
+        let atom = (xlib.XInternAtom)(display, c"I3_SOCKET_PATH".as_ptr(), xlib::True);
+        let mut path = None;
+
+        let mut actual_type = 0;
+        let mut actual_format = 0;
+        let mut nitems = 0;
+        let mut bytes_after = 0;
+        let mut prop = std::ptr::null_mut();
+
+        if atom != 0
+            && (xlib.XGetWindowProperty)(
+                display,
+                (xlib.XDefaultRootWindow)(display),
+                atom,
+                0,
+                4096,
+                xlib::False,
+                xlib::AnyPropertyType as u64,
+                &mut actual_type,
+                &mut actual_format,
+                &mut nitems,
+                &mut bytes_after,
+                &mut prop,
+            ) == xlib::Success as i32
+            && !prop.is_null()
+        {
+            let bytes = std::slice::from_raw_parts(prop, nitems as usize);
+            path = Some(PathBuf::from(String::from_utf8_lossy(bytes).into_owned()));
+            (xlib.XFree)(prop as *mut _);
+        }
+
+        (xlib.XCloseDisplay)(display);
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn add_window_properties_fills_in_wayland_windows() {
+        let mut tree = json!({
+            "nodes": [
+                {"id": 1, "name": "~/src", "app_id": "foot", "window_properties": null},
+                {"id": 2, "app_id": null, "window_properties": {"class": "Firefox"}},
+            ],
+            "floating_nodes": [{"id": 3, "app_id": "mpv"}],
+        });
+        add_window_properties(&mut tree);
+
+        assert_eq!(
+            tree["nodes"][0]["window_properties"],
+            json!({"class": "foot", "instance": "foot", "title": "~/src"})
+        );
+        assert_eq!(
+            tree["nodes"][1]["window_properties"],
+            json!({"class": "Firefox"})
+        );
+        assert_eq!(
+            tree["floating_nodes"][0]["window_properties"],
+            json!({"class": "mpv", "instance": "mpv", "title": null})
+        );
+        assert!(tree.get("window_properties").is_none());
+    }
+
+    #[test]
+    fn sway_is_detected_by_its_socket_variable() {
+        let both = |var: &str| Some(PathBuf::from(format!("/run/user/1000/{var}")));
+        let i3 = |var: &str| (var == "I3SOCK").then(|| PathBuf::from("/tmp/i3/ipc-socket"));
+        let none = |_: &str| None;
+
+        // sway exports I3SOCK too
+        assert!(is_sway(None, both));
+        assert!(!is_sway(None, i3));
+        assert!(!is_sway(None, none));
+        assert!(!is_sway(Some("i3"), both));
+        assert!(is_sway(Some("sway"), none));
+    }
+
+    #[test]
+    fn add_window_properties_keeps_x11_windows() {
+        let properties = json!({"class": "XTerm", "instance": "xterm", "title": "xterm"});
+        let mut node = json!({"app_id": "xterm", "window_properties": properties.clone()});
+        add_window_properties(&mut node);
+        assert_eq!(node["window_properties"], properties);
+    }
+}
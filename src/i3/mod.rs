@@ -0,0 +1,711 @@
+mod backend;
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, Lua, LuaSerdeExt, SerializeOptions};
+
+use crate::{event_loop::EventSource, logger::log, settings::Settings, signals::Shutdown};
+
+pub use backend::{IpcBackend, select_backend};
+
+const MAGIC: &[u8; 6] = b"i3-ipc";
+
+// Message types of the i3 IPC protocol, see https://i3wm.org/docs/ipc.html
+pub const RUN_COMMAND: u32 = 0;
+pub const GET_WORKSPACES: u32 = 1;
+pub const SUBSCRIBE: u32 = 2;
+pub const GET_OUTPUTS: u32 = 3;
+pub const GET_TREE: u32 = 4;
+pub const GET_MARKS: u32 = 5;
+pub const GET_VERSION: u32 = 7;
+
+/// Events a Lua callback can subscribe to, not every backend emits all of them.
+pub const EVENTS: [&str; 10] = [
+    "workspace",
+    "output",
+    "mode",
+    "window",
+    "barconfig_update",
+    "binding",
+    "shutdown",
+    "tick",
+    "bar_state_update",
+    "input",
+];
+
+fn event_name(event_type: u32) -> Option<&'static str> {
+    match event_type {
+        0..=7 => EVENTS.get(event_type as usize).copied(),
+        // sway specific events
+        0x14 => Some("bar_state_update"),
+        0x15 => Some("input"),
+        _ => None,
+    }
+}
+
+// Replies to events have the highest bit set.
+const EVENT_BIT: u32 = 1 << 31;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Raw connection to the i3 IPC socket speaking the i3 wire format.
+pub struct I3Socket {
+    stream: UnixStream,
+}
+
+impl I3Socket {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+
+    pub fn send(&mut self, message_type: u32, payload: &str) -> io::Result<()> {
+        let mut msg = Vec::with_capacity(MAGIC.len() + 8 + payload.len());
+        msg.extend_from_slice(MAGIC);
+        msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        msg.extend_from_slice(&message_type.to_le_bytes());
+        msg.extend_from_slice(payload.as_bytes());
+        self.stream.write_all(&msg)
+    }
+
+    /// Reads one message and returns its type and the decoded JSON payload.
+    pub fn receive(&mut self) -> io::Result<(u32, serde_json::Value)> {
+        let mut header = [0u8; 14];
+        self.stream.read_exact(&mut header)?;
+        if &header[..6] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid i3 IPC magic string",
+            ));
+        }
+
+        let len = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+        let message_type = u32::from_le_bytes([header[10], header[11], header[12], header[13]]);
+
+        let mut payload = vec![0u8; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        let payload = serde_json::from_slice(&payload)?;
+        Ok((message_type, payload))
+    }
+
+    /// Waits for the reply to a message of `message_type` that was sent.
+    pub fn reply(&mut self, message_type: u32) -> io::Result<serde_json::Value> {
+        loop {
+            let (reply_type, reply) = self.receive()?;
+            // Events can only show up on subscribed sockets, skip them just in case
+            if reply_type == message_type {
+                return Ok(reply);
+            }
+        }
+    }
+}
+
+impl AsRawFd for I3Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// Lazily connected i3 socket that reconnects when i3 restarts or was not running yet.
+///
+/// Failed connection attempts are retried with an exponential backoff, so a missing i3 does not
+/// cost a connect on every call.
+pub struct I3Conn {
+    backend: Arc<dyn IpcBackend>,
+    socket: Option<I3Socket>,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Default for I3Conn {
+    fn default() -> Self {
+        Self::new(select_backend(&Settings::default()))
+    }
+}
+
+impl I3Conn {
+    pub fn new(backend: Arc<dyn IpcBackend>) -> Self {
+        Self {
+            backend,
+            socket: None,
+            backoff: Duration::ZERO,
+            retry_at: None,
+        }
+    }
+
+    /// Switches to the backend picked by the settings, the next call connects to its socket.
+    pub fn configure(&mut self, settings: &Settings) {
+        *self = Self::new(select_backend(settings));
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// Earliest point in time the next connection attempt is made, `None` if it was never
+    /// attempted or the last attempt succeeded.
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    pub fn socket(&mut self) -> io::Result<&mut I3Socket> {
+        if self.socket.is_none() {
+            if self.retry_at.is_some_and(|at| at > Instant::now()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "i3 IPC not available",
+                ));
+            }
+
+            match self
+                .backend
+                .socket_path()
+                .and_then(|path| I3Socket::connect(&path))
+            {
+                Ok(socket) => {
+                    if !self.backoff.is_zero() {
+                        log(format!("Connected to {} IPC", self.backend.name()));
+                    }
+                    self.socket = Some(socket);
+                    self.backoff = Duration::ZERO;
+                    self.retry_at = None;
+                }
+                Err(e) => {
+                    // Only log the first failure, not every retry
+                    if self.backoff.is_zero() {
+                        log(format!("{} integration unavailable: {e}", self.backend.name()));
+                    }
+                    self.backoff = (self.backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+                    self.retry_at = Some(Instant::now() + self.backoff);
+                    return Err(e);
+                }
+            }
+        }
+
+        self.socket
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    pub fn request(&mut self, message_type: u32, payload: &str) -> io::Result<serde_json::Value> {
+        let mut reply = self.send_request(message_type, payload)?;
+        self.backend.normalize(message_type, &mut reply);
+        Ok(reply)
+    }
+
+    fn send_request(&mut self, message_type: u32, payload: &str) -> io::Result<serde_json::Value> {
+        match self.socket()?.send(message_type, payload) {
+            Ok(()) => {}
+            // A stale connection is only noticed once we write to it (e.g. after `i3-msg
+            // restart`), the message never reached i3 so it is safe to send it again.
+            Err(e) if matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+            ) =>
+            {
+                log(format!("Lost i3 connection: {e}, reconnecting"));
+                self.disconnect();
+                self.socket()?
+                    .send(message_type, payload)
+                    .inspect_err(|_| self.disconnect())?;
+            }
+            Err(e) => {
+                self.disconnect();
+                return Err(e);
+            }
+        }
+
+        // Once i3 read the message it may have run it, a connection lost now is only dropped so
+        // the next call reconnects. Sending it again could run a command like `kill` twice.
+        self.socket()?
+            .reply(message_type)
+            .inspect_err(|_| self.disconnect())
+    }
+
+    pub fn disconnect(&mut self) {
+        self.socket = None;
+    }
+}
+
+/// Converts an i3 reply to a Lua value, JSON nulls become nil.
+pub fn to_lua(lua: &Lua, value: &serde_json::Value) -> mlua::Result<mlua::Value> {
+    let options = SerializeOptions::new()
+        .serialize_none_to_null(false)
+        .serialize_unit_to_null(false);
+    lua.to_value_with(value, options)
+}
+
+/// Collects the errors of all failed commands of a `RUN_COMMAND` reply.
+pub fn command_errors(reply: &serde_json::Value) -> Vec<String> {
+    reply
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|outcome| outcome["success"].as_bool() != Some(true))
+        .map(|outcome| {
+            outcome["error"]
+                .as_str()
+                .unwrap_or("unknown error")
+                .to_string()
+        })
+        .collect()
+}
+
+/// Returns the focused node of a `GET_TREE` reply.
+pub fn find_focused(node: &serde_json::Value) -> Option<&serde_json::Value> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(find_focused)
+}
+
+/// Returns the workspace containing the node with the given `id`.
+pub fn find_workspace(node: &serde_json::Value, id: u64) -> Option<&serde_json::Value> {
+    fn contains(node: &serde_json::Value, id: u64) -> bool {
+        node["id"].as_u64() == Some(id)
+            || ["nodes", "floating_nodes"]
+                .iter()
+                .filter_map(|key| node[key].as_array())
+                .flatten()
+                .any(|child| contains(child, id))
+    }
+
+    if node["type"].as_str() == Some("workspace") {
+        return contains(node, id).then_some(node);
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(|child| find_workspace(child, id))
+}
+
+/// Returns all nodes of a `GET_TREE` reply that hold a window, in tree order.
+pub fn windows(node: &serde_json::Value) -> Vec<&serde_json::Value> {
+    let children = ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .flat_map(windows);
+
+    node["window_properties"]
+        .is_object()
+        .then_some(node)
+        .into_iter()
+        .chain(children)
+        .collect()
+}
+
+/// Lua callbacks registered through `ck.i3.on`, keyed by event name.
+#[derive(Default, Clone)]
+pub struct I3Handlers(pub Arc<RwLock<HashMap<String, Vec<Function>>>>);
+crate::deref!(I3Handlers => Arc<RwLock<HashMap<String, Vec<Function>>>>);
+
+impl I3Handlers {
+    fn events(&self) -> Vec<String> {
+        self.read()
+            .map(|handlers| handlers.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Event source that listens on a dedicated i3 socket and hands events to the Lua callbacks.
+pub struct I3Events {
+    lua: Lua,
+    handlers: I3Handlers,
+    conn: I3Conn,
+    subscribed: Vec<String>,
+    created: Instant,
+}
+
+impl I3Events {
+    pub fn new(lua: Lua, handlers: I3Handlers, backend: Arc<dyn IpcBackend>) -> Self {
+        Self {
+            lua,
+            handlers,
+            conn: I3Conn::new(backend),
+            subscribed: Vec::new(),
+            created: Instant::now(),
+        }
+    }
+
+    /// Subscribes to events that got a handler since the last call. The reply is skipped when
+    /// it arrives in `dispatch`.
+    fn subscribe(&mut self) {
+        let new = self
+            .handlers
+            .events()
+            .into_iter()
+            .filter(|e| !self.subscribed.contains(e))
+            .collect::<Vec<_>>();
+
+        // Unsupported events are never sent, so they are marked as subscribed to not retry them
+        let (new, unsupported): (Vec<_>, Vec<_>) = new
+            .into_iter()
+            .partition(|e| self.conn.backend.supports_event(e));
+        unsupported.into_iter().for_each(|e| {
+            log(format!(
+                "ck.i3.on(\"{e}\") is not supported by {}",
+                self.conn.backend.name()
+            ));
+            self.subscribed.push(e);
+        });
+
+        if let Some(socket) = self.conn.socket.as_mut()
+            && !new.is_empty()
+        {
+            let payload = serde_json::to_string(&new).unwrap_or_default();
+            match socket.send(SUBSCRIBE, &payload) {
+                Ok(_) => self.subscribed.extend(new),
+                Err(e) => {
+                    log(format!("i3 subscribe failed: {e}"));
+                    self.conn.disconnect();
+                }
+            }
+        }
+    }
+
+    fn call_handlers(&self, event: &str, payload: serde_json::Value) {
+        let handlers = self
+            .handlers
+            .read()
+            .ok()
+            .and_then(|h| h.get(event).cloned())
+            .unwrap_or_default();
+
+        match to_lua(&self.lua, &payload) {
+            Ok(payload) => handlers.into_iter().for_each(|cb| {
+                if let Err(e) = cb.call::<()>(payload.clone()) {
+                    log(format!("ck.i3.on(\"{event}\") callback failed: {e}"));
+                }
+            }),
+            Err(e) => log(format!("Could not convert i3 event: {e}")),
+        }
+    }
+}
+
+impl EventSource for I3Events {
+    fn fd(&self) -> Option<RawFd> {
+        self.conn.socket.as_ref().map(|s| s.as_raw_fd())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        // While disconnected we wake up to reconnect, as long as someone is listening
+        if self.conn.is_connected() || self.handlers.events().is_empty() {
+            return None;
+        }
+        Some(self.conn.retry_at().unwrap_or(self.created))
+    }
+
+    fn pending(&mut self) -> bool {
+        // Handlers can be added from within callbacks, so subscriptions are kept up to date here
+        self.subscribe();
+        false
+    }
+
+    fn dispatch(&mut self) -> Option<Shutdown> {
+        if !self.conn.is_connected() {
+            if self.conn.socket().is_ok() {
+                // A new connection starts without any subscriptions
+                self.subscribed.clear();
+                self.subscribe();
+            }
+            return None;
+        }
+
+        let socket = self.conn.socket.as_mut()?;
+        match socket.receive() {
+            Ok((message_type, payload)) if message_type & EVENT_BIT != 0 => {
+                if let Some(event) = event_name(message_type & !EVENT_BIT) {
+                    self.call_handlers(event, payload);
+                }
+            }
+            // Reply to one of our subscribe requests
+            Ok(_) => {}
+            Err(e) => {
+                log(format!("Lost i3 event connection: {e}, reconnecting"));
+                self.conn.disconnect();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, path::PathBuf, sync::mpsc, thread};
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Listens on a fresh socket and runs `serve` for the first connection in a thread, the
+    /// socket file is removed once it is connected.
+    fn fake_i3(name: &str, serve: impl FnOnce(I3Socket) + Send + 'static) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("crusty-keys-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).expect("bind fake i3 socket");
+        let socket_file = path.clone();
+        thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                let _ = std::fs::remove_file(socket_file);
+                serve(I3Socket { stream });
+            }
+        });
+        path
+    }
+
+    /// Reads a request as i3 does, the payload of most requests is empty and not JSON.
+    fn read_request(i3: &mut I3Socket) -> (u32, String) {
+        let mut header = [0u8; 14];
+        i3.stream.read_exact(&mut header).expect("request header");
+        assert_eq!(&header[..6], MAGIC);
+        let len = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+        let message_type = u32::from_le_bytes([header[10], header[11], header[12], header[13]]);
+
+        let mut payload = vec![0u8; len as usize];
+        i3.stream.read_exact(&mut payload).expect("request payload");
+        (message_type, String::from_utf8_lossy(&payload).into_owned())
+    }
+
+    #[test]
+    fn request_round_trip() -> io::Result<()> {
+        let path = fake_i3("round-trip", |mut i3| {
+            let (message_type, payload) = read_request(&mut i3);
+            assert_eq!(message_type, RUN_COMMAND);
+            assert_eq!(payload, r#"["workspace 2"]"#);
+            i3.send(RUN_COMMAND, r#"[{"success":true}]"#)
+                .expect("reply");
+        });
+
+        let mut socket = I3Socket::connect(&path)?;
+        socket.send(RUN_COMMAND, r#"["workspace 2"]"#)?;
+        let reply = socket.reply(RUN_COMMAND)?;
+        assert_eq!(reply, json!([{"success": true}]));
+        Ok(())
+    }
+
+    #[test]
+    fn request_skips_events() -> io::Result<()> {
+        let path = fake_i3("events", |mut i3| {
+            let (message_type, _) = read_request(&mut i3);
+            i3.send(EVENT_BIT | 3, r#"{"change":"init"}"#)
+                .expect("event");
+            i3.send(message_type, r#"{"human_readable":"4.23"}"#)
+                .expect("reply");
+        });
+
+        let mut socket = I3Socket::connect(&path)?;
+        socket.send(GET_VERSION, "")?;
+        let reply = socket.reply(GET_VERSION)?;
+        assert_eq!(reply["human_readable"], "4.23");
+        Ok(())
+    }
+
+    #[test]
+    fn receive_rejects_other_protocols() -> io::Result<()> {
+        let path = fake_i3("magic", |mut i3| {
+            let _ = i3.stream.write_all(b"not-i3\x02\0\0\0\0\0\0\0{}");
+        });
+
+        let mut socket = I3Socket::connect(&path)?;
+        let error = socket.receive().expect_err("invalid magic");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn receive_reports_a_closed_socket() -> io::Result<()> {
+        let path = fake_i3("closed", |mut i3| {
+            read_request(&mut i3);
+        });
+
+        let mut socket = I3Socket::connect(&path)?;
+        socket.send(GET_TREE, "")?;
+        let error = socket.reply(GET_TREE).expect_err("closed socket");
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    fn tree() -> serde_json::Value {
+        json!({
+            "id": 1,
+            "type": "root",
+            "nodes": [{
+                "id": 2,
+                "type": "output",
+                "nodes": [
+                    {
+                        "id": 3,
+                        "type": "workspace",
+                        "name": "1",
+                        "nodes": [
+                            {"id": 4, "window_properties": {"class": "Alacritty"}},
+                            {"id": 5, "nodes": [
+                                {"id": 6, "focused": true, "window_properties": {"class": "firefox"}},
+                            ]},
+                        ],
+                    },
+                    {
+                        "id": 7,
+                        "type": "workspace",
+                        "name": "2",
+                        "nodes": [],
+                        "floating_nodes": [
+                            {"id": 8, "window_properties": {"class": "mpv"}},
+                        ],
+                    },
+                ],
+            }],
+        })
+    }
+
+    struct FakeBackend(PathBuf);
+
+    impl IpcBackend for FakeBackend {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn socket_path(&self) -> io::Result<PathBuf> {
+            Ok(self.0.clone())
+        }
+
+        fn supports_event(&self, _event: &str) -> bool {
+            true
+        }
+    }
+
+    /// Listens on a fresh socket that `conn` is connected to, like a socket kept from an
+    /// earlier request.
+    fn connected_i3(name: &str) -> io::Result<(UnixListener, I3Conn, PathBuf)> {
+        let path = std::env::temp_dir().join(format!("crusty-keys-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let mut conn = I3Conn::new(Arc::new(FakeBackend(path.clone())));
+        conn.socket()?;
+        Ok((listener, conn, path))
+    }
+
+    fn accept(listener: &UnixListener) -> I3Socket {
+        I3Socket {
+            stream: listener.accept().expect("connection").0,
+        }
+    }
+
+    #[test]
+    fn request_is_sent_again_when_i3_closed_the_socket_before() -> io::Result<()> {
+        let (listener, mut conn, path) = connected_i3("restart")?;
+        let (closed, is_closed) = mpsc::channel();
+
+        let server = thread::spawn(move || {
+            // i3 restarted and closed the old connection before anything was sent
+            drop(accept(&listener));
+            closed.send(()).expect("closed");
+
+            let mut i3 = accept(&listener);
+            let (message_type, payload) = read_request(&mut i3);
+            i3.send(message_type, r#"[{"success":true}]"#)
+                .expect("reply");
+            payload
+        });
+
+        is_closed.recv().expect("closed");
+        let reply = conn.request(RUN_COMMAND, "reload");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(reply?, json!([{"success": true}]));
+        assert!(conn.is_connected());
+        assert_eq!(server.join().expect("fake i3"), "reload");
+        Ok(())
+    }
+
+    #[test]
+    fn request_read_by_i3_is_not_sent_again() -> io::Result<()> {
+        let (listener, mut conn, path) = connected_i3("read")?;
+
+        let server = thread::spawn(move || {
+            // i3 read the request, maybe ran it, and closed the connection without a reply
+            let mut old = accept(&listener);
+            let (_, first) = read_request(&mut old);
+            drop(old);
+
+            let mut i3 = accept(&listener);
+            let (message_type, second) = read_request(&mut i3);
+            i3.send(message_type, r#"[{"success":true}]"#)
+                .expect("reply");
+            [first, second]
+        });
+
+        let error = conn
+            .request(RUN_COMMAND, "workspace next")
+            .expect_err("closed socket");
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!conn.is_connected());
+
+        // The next call connects again
+        let reply = conn.request(RUN_COMMAND, "kill");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(reply?, json!([{"success": true}]));
+        assert_eq!(server.join().expect("fake i3"), ["workspace next", "kill"]);
+        Ok(())
+    }
+
+    #[test]
+    fn command_errors_lists_failed_commands() {
+        let reply = json!([
+            {"success": true},
+            {"success": false, "error": "No such workspace"},
+            {"success": false},
+        ]);
+        assert_eq!(
+            command_errors(&reply),
+            ["No such workspace", "unknown error"]
+        );
+        assert!(command_errors(&json!([{"success": true}])).is_empty());
+        assert!(command_errors(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn find_focused_searches_all_children() {
+        let tree = tree();
+        assert_eq!(find_focused(&tree).map(|node| &node["id"]), Some(&json!(6)));
+        assert!(find_focused(&json!({"id": 1, "nodes": []})).is_none());
+    }
+
+    #[test]
+    fn find_workspace_returns_the_enclosing_workspace() {
+        let tree = tree();
+        let name = |id| find_workspace(&tree, id).map(|ws| ws["name"].clone());
+        assert_eq!(name(6), Some(json!("1")));
+        assert_eq!(name(8), Some(json!("2")));
+        assert_eq!(name(7), Some(json!("2")));
+        assert_eq!(name(2), None);
+        assert_eq!(name(42), None);
+    }
+
+    #[test]
+    fn windows_are_listed_in_tree_order() {
+        let tree = tree();
+        let ids = windows(&tree)
+            .into_iter()
+            .map(|node| node["id"].as_u64())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [Some(4), Some(6), Some(8)]);
+    }
+}
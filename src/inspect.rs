@@ -0,0 +1,167 @@
+use std::{collections::HashMap, ffi::c_ulong, os::fd::RawFd, rc::Rc};
+
+use x11_dl::xlib::{self, ControlMask, ShiftMask};
+
+use crate::{
+    KeyMap,
+    event_loop::EventSource,
+    key_maps::kbcode::{KbModifierCode, key_name},
+    settings::Settings,
+    signals::Shutdown,
+    x11_kb,
+    xconn::Display,
+    xinput::{RawEvent, XInput},
+    xkb,
+};
+
+/// Modifiers by the name keymaps use for them, in the order they are written in a binding.
+const MODIFIERS: [(&str, KbModifierCode); 5] = [
+    ("<super>", KbModifierCode::Super),
+    ("<hyper>", KbModifierCode::Hyper),
+    ("<alt>", KbModifierCode::Alt),
+    ("<meta>", KbModifierCode::Meta),
+    ("<altgr>", KbModifierCode::AltGr),
+];
+
+/// Prints every key press together with the bindings that match it, for `crusty-keys inspect`.
+/// Keys are only listened to, nothing is grabbed, so the keymaps of a running daemon still work.
+pub struct Inspector<'a> {
+    x: Rc<Display>,
+    xinput: XInput,
+    /// The keymaps with the keycode and modifier mask of each key they are grabbed on, found in
+    /// the layout that is active when inspecting starts.
+    keymaps: Vec<(&'a KeyMap, Vec<(i32, u32)>)>,
+    masks: HashMap<c_ulong, u32>,
+    ignored: u32,
+}
+
+impl<'a> Inspector<'a> {
+    /// Returns None if the X server lacks XInput2, which is needed to see keys without grabbing
+    /// them.
+    pub fn new(x: Rc<Display>, keymaps: &'a [Rc<KeyMap>], settings: &Settings) -> Option<Self> {
+        let xinput = XInput::open(&x)?;
+        xinput.select_raw_events(&x);
+
+        let masks = xkb::modifier_masks(&x);
+        let ignored = x11_kb::ignored_mask(&masks, settings.ignore_modifiers.as_deref());
+        let group = xkb::current_group(&x);
+        let keymaps = keymaps
+            .iter()
+            .map(|keymap| {
+                let mask = keymap.map.modifiers.mask(&masks);
+                let keys = x11_kb::keycodes(&x, keymap, group)
+                    .into_iter()
+                    .map(|(keycode, shift)| (keycode, mask | shift))
+                    .collect();
+                (keymap.as_ref(), keys)
+            })
+            .collect();
+
+        println!("Press keys to inspect them, Ctrl+C to exit");
+        Some(Self {
+            x,
+            xinput,
+            keymaps,
+            masks,
+            ignored,
+        })
+    }
+
+    /// The binding string for the modifiers in `mask`, e.g. "<super>+<shift>".
+    fn modifier_names(&self, mask: u32) -> Vec<String> {
+        let mut names = (1..=5)
+            .map(|n| xlib::Mod1Mask << (n - 1))
+            .filter(|bit| mask & bit != 0)
+            .map(|bit| {
+                MODIFIERS
+                    .iter()
+                    .find(|(_, modifier)| modifier.mask(&self.masks) == bit)
+                    .map_or_else(
+                        || format!("<mod{}>", bit.trailing_zeros() - 2),
+                        |(name, _)| name.to_string(),
+                    )
+            })
+            .collect::<Vec<_>>();
+        if mask & ControlMask != 0 {
+            names.push("<ctrl>".to_string());
+        }
+        if mask & ShiftMask != 0 {
+            names.push("<shift>".to_string());
+        }
+        names
+    }
+
+    fn on_key_press(&self, keycode: u8) {
+        let sym = xkb::keysym(&self.x, keycode, xkb::current_group(&self.x), 0);
+        let sym_name = xkb::keysym_name(&self.x, sym).unwrap_or_else(|| "NoSymbol".to_string());
+        let mods = xkb::current_mods(&self.x);
+        println!("keycode {keycode}, keysym {sym_name} ({sym:#x}), modifiers {mods:#04x}");
+
+        if self.masks.contains_key(&sym) {
+            println!("  modifier key, bind it on its own with tap = true");
+            return;
+        }
+
+        let mask = mods & !self.ignored;
+        let modifiers = self.modifier_names(mask);
+        let binding = |key: String| {
+            modifiers
+                .iter()
+                .cloned()
+                .chain([key])
+                .collect::<Vec<_>>()
+                .join("+")
+        };
+        match key_name(sym as u32) {
+            Some(key) => println!(
+                "  binding {} or {}",
+                binding(key),
+                binding(format!("[{keycode}]"))
+            ),
+            None => println!("  binding {}", binding(format!("[{keycode}]"))),
+        }
+
+        let matches = self
+            .keymaps
+            .iter()
+            .filter(|(_, keys)| keys.contains(&(i32::from(keycode), mask)))
+            .filter(|(keymap, _)| {
+                keymap
+                    .map
+                    .modifiers
+                    .sides()
+                    .into_iter()
+                    .all(|sym| xkb::is_pressed(&self.x, c_ulong::from(sym)))
+            })
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            println!("  no keymap is set for it");
+        }
+        matches.into_iter().for_each(|(keymap, _)| {
+            println!("  matches keymap \"{}\" at {}", keymap.s, keymap.location)
+        });
+    }
+}
+
+impl EventSource for Inspector<'_> {
+    fn fd(&self) -> Option<RawFd> {
+        Some(unsafe { (self.x.xlib.XConnectionNumber)(self.x.ptr) })
+    }
+
+    fn pending(&mut self) -> bool {
+        unsafe { (self.x.xlib.XPending)(self.x.ptr) > 0 }
+    }
+
+    fn dispatch(&mut self) -> Option<Shutdown> {
+        while self.pending() {
+            let mut event: xlib::XEvent = unsafe { std::mem::zeroed() };
+            unsafe {
+                (self.x.xlib.XNextEvent)(self.x.ptr, &mut event);
+            }
+            if let Some(RawEvent::KeyPress(keycode)) = self.xinput.raw_event(&self.x, &mut event) {
+                self.on_key_press(keycode);
+            }
+        }
+        None
+    }
+}
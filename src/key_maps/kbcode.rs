@@ -1,14 +1,30 @@
+use std::collections::HashMap;
+
 use x11_dl::{
     keysym,
     xlib::{self},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KbModifierCode {
     Alt,
     Ctrl,
     Super,
     Shift,
+    Hyper,
+    Meta,
+    AltGr,
+    /// One of the raw modifiers Mod1 to Mod5.
+    Mod(u8),
+
+    LAlt,
+    RAlt,
+    LCtrl,
+    RCtrl,
+    LSuper,
+    RSuper,
+    LShift,
+    RShift,
 }
 pub struct KbCode(u32);
 
@@ -19,16 +35,97 @@ impl KbCode {
 }
 
 impl KbModifierCode {
-    pub fn to_code(&self) -> u32 {
+    /// The mask of modifiers that are the same on every server.
+    pub fn fixed_mask(&self) -> Option<u32> {
+        match self {
+            KbModifierCode::Shift | KbModifierCode::LShift | KbModifierCode::RShift => {
+                Some(xlib::ShiftMask)
+            }
+            KbModifierCode::Ctrl | KbModifierCode::LCtrl | KbModifierCode::RCtrl => {
+                Some(xlib::ControlMask)
+            }
+            KbModifierCode::Mod(n) => Some(xlib::Mod1Mask << (n - 1)),
+            _ => None,
+        }
+    }
+
+    /// The keys that set this modifier, its mask is whichever modifier these keys are mapped to.
+    pub fn keysyms(&self) -> &'static [u32] {
         match self {
-            KbModifierCode::Alt => xlib::Mod1Mask,
-            KbModifierCode::Ctrl => xlib::ControlMask,
-            KbModifierCode::Super => xlib::Mod4Mask,
-            KbModifierCode::Shift => xlib::ShiftMask,
+            KbModifierCode::Alt => &[keysym::XK_Alt_L, keysym::XK_Alt_R],
+            KbModifierCode::Super => &[keysym::XK_Super_L, keysym::XK_Super_R],
+            KbModifierCode::Hyper => &[keysym::XK_Hyper_L, keysym::XK_Hyper_R],
+            KbModifierCode::Meta => &[keysym::XK_Meta_L, keysym::XK_Meta_R],
+            KbModifierCode::AltGr => &[keysym::XK_ISO_Level3_Shift, keysym::XK_Mode_switch],
+            KbModifierCode::LAlt => &[keysym::XK_Alt_L],
+            KbModifierCode::RAlt => &[keysym::XK_Alt_R],
+            KbModifierCode::LSuper => &[keysym::XK_Super_L],
+            KbModifierCode::RSuper => &[keysym::XK_Super_R],
+            _ => &[],
+        }
+    }
+
+    /// The modifier mask with `masks` mapping the keysyms of modifier keys to their mask.
+    pub fn mask(&self, masks: &HashMap<u64, u32>) -> u32 {
+        self.fixed_mask()
+            .or_else(|| {
+                self.keysyms()
+                    .iter()
+                    .find_map(|sym| masks.get(&u64::from(*sym)).copied())
+            })
+            .unwrap_or_else(|| self.default_mask())
+    }
+
+    /// The mask used when none of the keys of the modifier is mapped.
+    pub fn default_mask(&self) -> u32 {
+        match self {
+            KbModifierCode::Alt
+            | KbModifierCode::LAlt
+            | KbModifierCode::RAlt
+            | KbModifierCode::Meta => xlib::Mod1Mask,
+            KbModifierCode::Super
+            | KbModifierCode::LSuper
+            | KbModifierCode::RSuper
+            | KbModifierCode::Hyper => xlib::Mod4Mask,
+            KbModifierCode::AltGr => xlib::Mod5Mask,
+            _ => self.fixed_mask().unwrap_or_default(),
+        }
+    }
+
+    /// The keys that trigger a tap of this modifier, for `<mod1>` to `<mod5>` the keys mapped to
+    /// it in `masks`.
+    pub fn tap_keysyms(&self, masks: &HashMap<u64, u32>) -> Vec<u32> {
+        match self {
+            KbModifierCode::Shift => vec![keysym::XK_Shift_L, keysym::XK_Shift_R],
+            KbModifierCode::Ctrl => vec![keysym::XK_Control_L, keysym::XK_Control_R],
+            KbModifierCode::Mod(_) => masks
+                .iter()
+                .filter(|(_, mask)| Some(**mask) == self.fixed_mask())
+                .map(|(sym, _)| *sym as u32)
+                .collect(),
+            modifier => modifier
+                .side_keysym()
+                .map_or_else(|| modifier.keysyms().to_vec(), |sym| vec![sym]),
+        }
+    }
+
+    /// The key that has to be held for side specific modifiers like `<lsuper>`.
+    pub fn side_keysym(&self) -> Option<u32> {
+        match self {
+            KbModifierCode::LAlt => Some(keysym::XK_Alt_L),
+            KbModifierCode::RAlt => Some(keysym::XK_Alt_R),
+            KbModifierCode::LCtrl => Some(keysym::XK_Control_L),
+            KbModifierCode::RCtrl => Some(keysym::XK_Control_R),
+            KbModifierCode::LSuper => Some(keysym::XK_Super_L),
+            KbModifierCode::RSuper => Some(keysym::XK_Super_R),
+            KbModifierCode::LShift => Some(keysym::XK_Shift_L),
+            KbModifierCode::RShift => Some(keysym::XK_Shift_R),
+            _ => None,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum KbSym {
     Enter,
     Space,
@@ -106,8 +203,8 @@ impl KbSym {
             KbSym::Tab => keysym::XK_Tab,
             KbSym::Minus => keysym::XK_minus,
             KbSym::Equals => keysym::XK_equal,
-            KbSym::Quote => keysym::XK_quoteleft,
-            KbSym::Backtick => keysym::XK_quoteright,
+            KbSym::Quote => keysym::XK_apostrophe,
+            KbSym::Backtick => keysym::XK_grave,
             KbSym::Backslash => keysym::XK_backslash,
             KbSym::Slash => keysym::XK_slash,
             KbSym::Semicolon => keysym::XK_semicolon,
@@ -147,14 +244,14 @@ impl KbSym {
                 10 => keysym::XK_F10,
                 11 => keysym::XK_F11,
                 12 => keysym::XK_F12,
-                13 => keysym::XK_F14,
-                14 => keysym::XK_F15,
-                15 => keysym::XK_F16,
-                16 => keysym::XK_F17,
-                17 => keysym::XK_F18,
-                18 => keysym::XK_F19,
-                19 => keysym::XK_F20,
-                20 => keysym::XK_F21,
+                13 => keysym::XK_F13,
+                14 => keysym::XK_F14,
+                15 => keysym::XK_F15,
+                16 => keysym::XK_F16,
+                17 => keysym::XK_F17,
+                18 => keysym::XK_F18,
+                19 => keysym::XK_F19,
+                20 => keysym::XK_F20,
                 21 => keysym::XK_F21,
                 22 => keysym::XK_F22,
                 23 => keysym::XK_F23,
@@ -209,12 +306,127 @@ impl KbSym {
                 '8' => keysym::XK_8,
                 '9' => keysym::XK_9,
                 '0' => keysym::XK_0,
-                _ => unreachable!(),
+                // The keysyms of all other printable ASCII characters equal their code point
+                c => *c as u32,
             },
         }
     }
 }
 
+/// Names of the keys in keymaps, besides single characters and `[keycode]`.
+pub const KEY_NAMES: &[(&str, KbSym)] = &[
+    ("<enter>", KbSym::Enter),
+    ("<space>", KbSym::Space),
+    ("<backspace>", KbSym::Backspace),
+    ("<esc>", KbSym::Esc),
+
+    ("<end>", KbSym::End),
+    ("<home>", KbSym::Home),
+    ("<insert>", KbSym::Insert),
+    ("<del>", KbSym::Del),
+
+    ("<up>", KbSym::Up),
+    ("<down>", KbSym::Down),
+    ("<right>", KbSym::Right),
+    ("<left>", KbSym::Left),
+
+    ("<pgdown>", KbSym::PgDown),
+    ("<pgup>", KbSym::PgUp),
+
+    ("<tab>", KbSym::Tab),
+
+    ("<equals>", KbSym::Equals),
+    ("<minus>", KbSym::Minus),
+
+    ("<quote>", KbSym::Quote),
+    ("<backtick>", KbSym::Backtick),
+    ("<backslash>", KbSym::Backslash),
+    ("<slash>", KbSym::Slash),
+    ("<semicolon>", KbSym::Semicolon),
+
+    ("<comma>", KbSym::Comma),
+    ("<period>", KbSym::Period),
+
+    ("<f1>", KbSym::F(1)),
+    ("<f2>", KbSym::F(2)),
+    ("<f3>", KbSym::F(3)),
+    ("<f4>", KbSym::F(4)),
+    ("<f5>", KbSym::F(5)),
+    ("<f6>", KbSym::F(6)),
+    ("<f7>", KbSym::F(7)),
+    ("<f8>", KbSym::F(8)),
+    ("<f9>", KbSym::F(9)),
+    ("<f10>", KbSym::F(10)),
+    ("<f11>", KbSym::F(11)),
+    ("<f12>", KbSym::F(12)),
+    ("<f13>", KbSym::F(13)),
+    ("<f14>", KbSym::F(14)),
+    ("<f15>", KbSym::F(15)),
+    ("<f16>", KbSym::F(16)),
+    ("<f17>", KbSym::F(17)),
+    ("<f18>", KbSym::F(18)),
+    ("<f19>", KbSym::F(19)),
+    ("<f20>", KbSym::F(20)),
+    ("<f21>", KbSym::F(21)),
+    ("<f22>", KbSym::F(22)),
+    ("<f23>", KbSym::F(23)),
+    ("<f24>", KbSym::F(24)),
+    ("<f25>", KbSym::F(25)),
+    ("<f26>", KbSym::F(26)),
+    ("<f27>", KbSym::F(27)),
+    ("<f28>", KbSym::F(28)),
+    ("<f29>", KbSym::F(29)),
+    ("<f30>", KbSym::F(30)),
+    ("<f31>", KbSym::F(31)),
+    ("<f32>", KbSym::F(32)),
+    ("<f33>", KbSym::F(33)),
+    ("<f34>", KbSym::F(34)),
+    ("<f35>", KbSym::F(35)),
+
+    ("<kp0>", KbSym::KP0),
+    ("<kp1>", KbSym::KP1),
+    ("<kp2>", KbSym::KP2),
+    ("<kp3>", KbSym::KP3),
+    ("<kp4>", KbSym::KP4),
+    ("<kp5>", KbSym::KP5),
+    ("<kp6>", KbSym::KP6),
+    ("<kp7>", KbSym::KP7),
+    ("<kp8>", KbSym::KP8),
+    ("<kp9>", KbSym::KP9),
+
+    ("<xf86audiomute>", KbSym::XF86AudioMute),
+    ("<xf86audiomicmute>", KbSym::XF86AudioMicMute),
+    ("<xf86audionext>", KbSym::XF86AudioNext),
+    ("<xf86audioprev>", KbSym::XF86AudioPrev),
+    ("<xf86audiostop>", KbSym::XF86AudioStop),
+    ("<xf86audioplay>", KbSym::XF86AudioPlay),
+    ("<xf86audiolowervolume>", KbSym::XF86AudioLowerVolue),
+    ("<xf86audioraisevolume>", KbSym::XF86AudioRaiseVolume),
+
+    // Symbols that are usually on the shifted level, named like their X keysym
+    ("<exclam>", KbSym::Char('!')),
+    ("<at>", KbSym::Char('@')),
+    ("<numbersign>", KbSym::Char('#')),
+    ("<dollar>", KbSym::Char('$')),
+    ("<percent>", KbSym::Char('%')),
+    ("<asciicircum>", KbSym::Char('^')),
+    ("<ampersand>", KbSym::Char('&')),
+    ("<asterisk>", KbSym::Char('*')),
+    ("<parenleft>", KbSym::Char('(')),
+    ("<parenright>", KbSym::Char(')')),
+    ("<underscore>", KbSym::Char('_')),
+    ("<plus>", KbSym::Char('+')),
+    ("<braceleft>", KbSym::Char('{')),
+    ("<braceright>", KbSym::Char('}')),
+    ("<bar>", KbSym::Char('|')),
+    ("<colon>", KbSym::Char(':')),
+    ("<quotedbl>", KbSym::Char('"')),
+    ("<less>", KbSym::Char('<')),
+    ("<greater>", KbSym::Char('>')),
+    ("<question>", KbSym::Char('?')),
+    ("<asciitilde>", KbSym::Char('~')),
+];
+
 pub enum KeyKind {
     Mod(KbModifierCode),
     KeySym(KbSym),
@@ -245,99 +457,30 @@ impl From<&str> for KeyKind {
             "<ctrl>" => KbModifierCode::Ctrl.into(),
             "<alt>" => KbModifierCode::Alt.into(),
             "<shift>" => KbModifierCode::Shift.into(),
-
-            "<enter>" => KbSym::Enter.into(),
-            "<space>" => KbSym::Space.into(),
-            "<backspace>" => KbSym::Backspace.into(),
-            "<esc>" => KbSym::Esc.into(),
-
-            "<end>" => KbSym::End.into(),
-            "<home>" => KbSym::Home.into(),
-            "<insert>" => KbSym::Insert.into(),
-            "<del>" => KbSym::Del.into(),
-
-            "<up>" => KbSym::Up.into(),
-            "<down>" => KbSym::Down.into(),
-            "<right>" => KbSym::Right.into(),
-            "<left>" => KbSym::Left.into(),
-
-            "<pgdown>" => KbSym::PgDown.into(),
-            "<pgup>" => KbSym::PgUp.into(),
-
-            "<tab>" => KbSym::Tab.into(),
-
-            "<equals>" => KbSym::Equals.into(),
-            "<minus>" => KbSym::Minus.into(),
-
-            "<quote>" => KbSym::Quote.into(),
-            "<backtick>" => KbSym::Backtick.into(),
-            "<backslash>" => KbSym::Backslash.into(),
-            "<slash>" => KbSym::Slash.into(),
-            "<semicolon>" => KbSym::Semicolon.into(),
-
-            "<comma>" => KbSym::Comma.into(),
-            "<period>" => KbSym::Period.into(),
-
-            "<f1>" => KbSym::F(1).into(),
-            "<f2>" => KbSym::F(2).into(),
-            "<f3>" => KbSym::F(3).into(),
-            "<f4>" => KbSym::F(4).into(),
-            "<f5>" => KbSym::F(5).into(),
-            "<f6>" => KbSym::F(6).into(),
-            "<f7>" => KbSym::F(7).into(),
-            "<f8>" => KbSym::F(8).into(),
-            "<f9>" => KbSym::F(9).into(),
-            "<f10>" => KbSym::F(10).into(),
-            "<f11>" => KbSym::F(11).into(),
-            "<f12>" => KbSym::F(12).into(),
-            "<f13>" => KbSym::F(13).into(),
-            "<f14>" => KbSym::F(14).into(),
-            "<f15>" => KbSym::F(15).into(),
-            "<f16>" => KbSym::F(16).into(),
-            "<f17>" => KbSym::F(17).into(),
-            "<f18>" => KbSym::F(18).into(),
-            "<f19>" => KbSym::F(19).into(),
-            "<f20>" => KbSym::F(20).into(),
-            "<f21>" => KbSym::F(21).into(),
-            "<f22>" => KbSym::F(22).into(),
-            "<f23>" => KbSym::F(23).into(),
-            "<f24>" => KbSym::F(24).into(),
-            "<f25>" => KbSym::F(25).into(),
-            "<f26>" => KbSym::F(26).into(),
-            "<f27>" => KbSym::F(27).into(),
-            "<f28>" => KbSym::F(28).into(),
-            "<f29>" => KbSym::F(29).into(),
-            "<f30>" => KbSym::F(30).into(),
-            "<f31>" => KbSym::F(31).into(),
-            "<f32>" => KbSym::F(32).into(),
-            "<f33>" => KbSym::F(33).into(),
-            "<f34>" => KbSym::F(34).into(),
-            "<f35>" => KbSym::F(35).into(),
-
-            "<KP0>" => KbSym::KP0.into(),
-            "<KP1>" => KbSym::KP1.into(),
-            "<KP2>" => KbSym::KP2.into(),
-            "<KP3>" => KbSym::KP3.into(),
-            "<KP4>" => KbSym::KP4.into(),
-            "<KP5>" => KbSym::KP5.into(),
-            "<KP6>" => KbSym::KP6.into(),
-            "<KP7>" => KbSym::KP7.into(),
-            "<KP8>" => KbSym::KP8.into(),
-            "<KP9>" => KbSym::KP9.into(),
-
-            "<XF86AudioMute>" => KbSym::XF86AudioMute.into(),
-            "<XF86AudioMicMute>" => KbSym::XF86AudioMicMute.into(),
-            "<XF86AudioNext>" => KbSym::XF86AudioNext.into(),
-            "<XF86AudioPrev>" => KbSym::XF86AudioPrev.into(),
-            "<XF86AudioStop>" => KbSym::XF86AudioStop.into(),
-            "<XF86AudioPlay>" => KbSym::XF86AudioPlay.into(),
-            "<XF86AudioLowerVolume>" => KbSym::XF86AudioLowerVolue.into(),
-            "<XF86AudioRaiseVolume>" => KbSym::XF86AudioRaiseVolume.into(),
+            "<hyper>" => KbModifierCode::Hyper.into(),
+            "<meta>" => KbModifierCode::Meta.into(),
+            "<altgr>" => KbModifierCode::AltGr.into(),
+            "<mod1>" => KbModifierCode::Mod(1).into(),
+            "<mod2>" => KbModifierCode::Mod(2).into(),
+            "<mod3>" => KbModifierCode::Mod(3).into(),
+            "<mod4>" => KbModifierCode::Mod(4).into(),
+            "<mod5>" => KbModifierCode::Mod(5).into(),
+
+            "<lalt>" => KbModifierCode::LAlt.into(),
+            "<ralt>" => KbModifierCode::RAlt.into(),
+            "<lctrl>" => KbModifierCode::LCtrl.into(),
+            "<rctrl>" => KbModifierCode::RCtrl.into(),
+            "<lsuper>" => KbModifierCode::LSuper.into(),
+            "<rsuper>" => KbModifierCode::RSuper.into(),
+            "<lshift>" => KbModifierCode::LShift.into(),
+            "<rshift>" => KbModifierCode::RShift.into(),
 
             _ => {
-                if value.len() == 1
+                if let Some((_, sym)) = KEY_NAMES.iter().find(|(name, _)| *name == value) {
+                    sym.clone().into()
+                } else if value.len() == 1
                     && let Some(char) = value.chars().next()
-                    && char.is_ascii_alphanumeric()
+                    && char.is_ascii_graphic()
                 {
                     KbSym::Char(char).into()
                 } else {
@@ -351,6 +494,18 @@ impl From<&str> for KeyKind {
     }
 }
 
+/// The name keymaps use for the keysym `sym`, None if the key can only be bound by keycode.
+pub fn key_name(sym: u32) -> Option<String> {
+    if let Some((name, _)) = KEY_NAMES.iter().find(|(_, kb_sym)| kb_sym.to_code() == sym) {
+        return Some(name.to_string());
+    }
+    // Keymaps are lowercased, "A" would bind "a"
+    char::from_u32(sym)
+        .filter(|c| c.is_ascii_graphic() && !c.is_ascii_uppercase())
+        .filter(|c| KbSym::Char(*c).to_code() == sym)
+        .map(String::from)
+}
+
 fn extract_keycode(code: &str) -> Option<u32> {
     regex::Regex::new(r#"\[(.*)\]"#)
         .ok()?
@@ -360,3 +515,74 @@ fn extract_keycode(code: &str) -> Option<u32> {
         .next()
         .flatten()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(s: &str) -> Option<u32> {
+        match KeyKind::from(s) {
+            KeyKind::KeySym(sym) => Some(sym.to_code()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn key_names_parse_in_any_case() {
+        for (name, sym) in KEY_NAMES {
+            assert_eq!(code(name), Some(sym.to_code()), "{name}");
+            assert_eq!(code(&name.to_uppercase()), Some(sym.to_code()), "{name}");
+        }
+    }
+
+    #[test]
+    fn key_names_are_unique() {
+        for (index, (name, _)) in KEY_NAMES.iter().enumerate() {
+            assert!(
+                KEY_NAMES[index + 1..]
+                    .iter()
+                    .all(|(other, _)| other != name),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn key_names_round_trip() {
+        let syms = KEY_NAMES
+            .iter()
+            .map(|(_, sym)| sym.to_code())
+            .chain((0x21..0x7f).filter(|sym| !(keysym::XK_A..=keysym::XK_Z).contains(sym)));
+        for sym in syms {
+            let name = key_name(sym).unwrap_or_else(|| panic!("no name for {sym:#x}"));
+            assert_eq!(code(&name), Some(sym), "{name}");
+        }
+    }
+
+    #[test]
+    fn key_name_is_none_for_unnamed_keysyms() {
+        assert_eq!(key_name(keysym::XK_Shift_L), None);
+        assert_eq!(key_name(keysym::XK_A), None);
+        assert_eq!(key_name(keysym::XK_eacute), None);
+    }
+
+    #[test]
+    fn single_characters_and_keycodes_parse() {
+        assert_eq!(code("a"), Some(keysym::XK_a));
+        assert_eq!(code("A"), Some(keysym::XK_a));
+        assert_eq!(code("!"), Some(keysym::XK_exclam));
+        assert_eq!(code("<quote>"), Some(keysym::XK_apostrophe));
+        assert_eq!(code("'"), Some(keysym::XK_apostrophe));
+        assert_eq!(code("<backtick>"), Some(keysym::XK_grave));
+        assert_eq!(code("`"), Some(keysym::XK_grave));
+        assert!(matches!(
+            KeyKind::from("[38]"),
+            KeyKind::KeyCode(KbCode(38))
+        ));
+        assert!(matches!(
+            KeyKind::from("<mod4>"),
+            KeyKind::Mod(KbModifierCode::Mod(4))
+        ));
+        assert!(matches!(KeyKind::from("<nope>"), KeyKind::Unknown));
+    }
+}
@@ -1,16 +1,104 @@
 pub mod kbcode;
 
-use std::{cmp::Ordering, ops::BitOrAssign, sync::{Arc, RwLock}};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Display,
+    ops::BitOrAssign,
+    rc::Rc,
+    sync::{Arc, RwLock},
+};
 
-use mlua::{Function, Table};
-use x11_dl::xlib::{LockMask, Mod2Mask};
+use mlua::{Function, Table, Value};
 
 use crate::key_maps::kbcode::{KbCode, KbModifierCode, KbSym, KeyKind};
 
+/// Which keys a symbol binding is grabbed on when several layouts (XKB groups) are configured.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Layout {
+    /// The key producing the symbol in the active layout, regrabbed when the layout changes.
+    Current,
+    /// Every key producing the symbol in any of the layouts.
+    #[default]
+    Any,
+    /// The key producing the symbol in the first layout, it stays on that physical key.
+    Physical,
+}
+
+impl Layout {
+    pub const NAMES: [&str; 3] = ["current", "any", "physical"];
+}
+
+impl TryFrom<&str> for Layout {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "current" => Ok(Layout::Current),
+            "any" => Ok(Layout::Any),
+            "physical" => Ok(Layout::Physical),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Callbacks picked by how a key is pressed, see [`KeyMapOptions::timing`].
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub tap: Option<Function>,
+    pub hold: Option<Function>,
+    pub double_tap: Option<Function>,
+    /// How long the key has to be held down for `hold`.
+    pub hold_ms: u64,
+    /// How long to wait for a second tap, only with `double_tap`.
+    pub double_tap_ms: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            tap: None,
+            hold: None,
+            double_tap: None,
+            hold_ms: 300,
+            double_tap_ms: 250,
+        }
+    }
+}
+
+impl Timing {
+    /// Without any of the timed callbacks the keymap runs as soon as the key is pressed.
+    pub fn is_empty(&self) -> bool {
+        self.tap.is_none() && self.hold.is_none() && self.double_tap.is_none()
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct KeyMapOptions {
     pub group: Option<String>,
     pub desc: Option<String>,
+    pub layout: Layout,
+    /// Bind a modifier on its own, triggered when it is released without another key pressed.
+    pub tap: bool,
+    /// Separate callbacks for a tap, a hold and a double tap of the key. The keymap callback
+    /// runs for a tap unless `tap` is a function.
+    pub timing: Timing,
+    /// Replace an earlier keymap bound to the same keys without a warning.
+    pub r#override: bool,
+}
+
+impl KeyMapOptions {
+    pub const KEYS: [&str; 9] = [
+        "group",
+        "desc",
+        "layout",
+        "tap",
+        "hold",
+        "hold_ms",
+        "double_tap",
+        "double_tap_ms",
+        "override",
+    ];
 }
 
 impl From<Table> for KeyMapOptions {
@@ -18,6 +106,22 @@ impl From<Table> for KeyMapOptions {
         KeyMapOptions {
             group: value.get("group").ok(),
             desc: value.get("desc").ok(),
+            layout: value
+                .get::<String>("layout")
+                .ok()
+                .and_then(|l| Layout::try_from(l.as_str()).ok())
+                .unwrap_or_default(),
+            tap: matches!(value.get::<Value>("tap"), Ok(Value::Boolean(true))),
+            r#override: value.get::<Option<bool>>("override").ok().flatten().unwrap_or_default(),
+            timing: Timing {
+                tap: value.get("tap").ok(),
+                hold: value.get("hold").ok(),
+                double_tap: value.get("double_tap").ok(),
+                hold_ms: value.get("hold_ms").unwrap_or(Timing::default().hold_ms),
+                double_tap_ms: value
+                    .get("double_tap_ms")
+                    .unwrap_or(Timing::default().double_tap_ms),
+            },
         }
     }
 }
@@ -27,76 +131,176 @@ pub struct KeyMap {
     pub s: String,
     pub map: Map,
     pub cb: Function,
+    /// Where the keymap is set in the config, as "file:line".
+    pub location: String,
 }
 
+/// The modifiers of a keymap. Most masks depend on the modifier mapping of the server, so they
+/// are only resolved when the keys are grabbed.
 #[derive(Default)]
-pub struct Modifier(u32);
+pub struct Modifier(Vec<KbModifierCode>);
 
 impl Modifier {
-    pub fn as_universal(&self) -> [u32; 4] {
-        [
-            self.0,
-            self.0 | Mod2Mask,
-            self.0 | LockMask,
-            self.0 | LockMask | Mod2Mask,
-        ]
+    /// The modifier mask with `masks` mapping the keysyms of modifier keys to their mask.
+    pub fn mask(&self, masks: &HashMap<u64, u32>) -> u32 {
+        self.0
+            .iter()
+            .fold(0, |mask, modifier| mask | modifier.mask(masks))
+    }
+
+    /// Whether Shift is one of the modifiers, on either side.
+    pub fn has_shift(&self) -> bool {
+        self.0.iter().any(|modifier| {
+            matches!(
+                modifier,
+                KbModifierCode::Shift | KbModifierCode::LShift | KbModifierCode::RShift
+            )
+        })
+    }
+
+    /// Keys that have to be held on top of the mask, for side specific modifiers.
+    pub fn sides(&self) -> Vec<u32> {
+        self.0.iter().filter_map(KbModifierCode::side_keysym).collect()
     }
-}
 
-crate::deref!(Modifier => u32);
+    /// `mask` combined with every combination of the `ignored` lock modifiers.
+    pub fn as_universal(mask: u32, ignored: u32) -> Vec<u32> {
+        let ignored = ignored & !mask;
+        let mut masks = vec![];
+        let mut locks = ignored;
+        loop {
+            masks.push(mask | locks);
+            if locks == 0 {
+                return masks;
+            }
+            locks = (locks - 1) & ignored;
+        }
+    }
+}
 
-impl From<u32> for Modifier {
-    fn from(value: u32) -> Self {
-        Modifier(value)
+impl PartialEq for Modifier {
+    /// The same modifiers in any order.
+    fn eq(&self, other: &Self) -> bool {
+        self.0.iter().all(|modifier| other.0.contains(modifier))
+            && other.0.iter().all(|modifier| self.0.contains(modifier))
     }
 }
 
 impl BitOrAssign<KbModifierCode> for Modifier {
     fn bitor_assign(&mut self, rhs: KbModifierCode) {
-        self.0 |= rhs.to_code()
+        self.0.push(rhs)
     }
 }
 
 pub enum KbCodeType{
     Sym(KbSym),
-    Code(KbCode)
+    Code(KbCode),
+    /// A modifier key pressed and released on its own.
+    Tap(KbModifierCode),
 }
 
+impl PartialEq for KbCodeType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (KbCodeType::Sym(a), KbCodeType::Sym(b)) => a.to_code() == b.to_code(),
+            (KbCodeType::Code(a), KbCodeType::Code(b)) => a.to_code() == b.to_code(),
+            (KbCodeType::Tap(a), KbCodeType::Tap(b)) => a == b,
+            _ => false,
+        }
+    }
+}
 
+/// Keymaps are equal when they bind the same keys, no matter how they are written, e.g.
+/// `<ctrl>+<shift>+a` and `<shift>+<ctrl>+a`.
+#[derive(PartialEq)]
 pub struct Map {
     pub modifiers: Modifier,
     pub code: KbCodeType,
 }
 
+#[derive(Debug)]
+pub enum MapError {
+    UnknownKey(String),
+    NoKey,
+    MultipleKeys(String, String),
+    NotAModifier(String),
+    /// A symbol only found on the shifted level of its key, combined with `<shift>`.
+    ShiftedSymbol(String),
+}
+
+impl Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapError::UnknownKey(key) => f.write_fmt(format_args!("unknown key \"{key}\"")),
+            MapError::NoKey => f.write_str(
+                "only modifiers, a key is missing (use tap = true to bind a modifier on its own)",
+            ),
+            MapError::MultipleKeys(a, b) => f.write_fmt(format_args!(
+                "more than one key (\"{a}\" and \"{b}\"), only modifiers can be combined"
+            )),
+            MapError::NotAModifier(key) => f.write_fmt(format_args!(
+                "\"{key}\" can't be tapped, only a single modifier key like <super> or <lalt> can"
+            )),
+            MapError::ShiftedSymbol(key) => f.write_fmt(format_args!(
+                "\"{key}\" is on the shifted level of its key and already implies <shift>, bind it without <shift>"
+            )),
+        }
+    }
+}
+
 impl TryFrom<&String> for Map {
-    type Error = ();
+    type Error = MapError;
     fn try_from(value: &String) -> Result<Self, Self::Error> {
         let event = value
             .split("+")
-            .fold(MapBuilder::default(), |mut map, s| {
+            .try_fold(MapBuilder::default(), |mut map, s| {
                 let s = s.trim();
-                match KeyKind::from(s) {
-                    KeyKind::Mod(kb_modifier) => map.modifiers |= kb_modifier,
-                    KeyKind::KeySym(kb_code) => map.code = Some(KbCodeType::Sym(kb_code)),
-                    KeyKind::KeyCode(kb_code) => map.code =  Some(KbCodeType::Code(kb_code)),
-                    KeyKind::Unknown => map.code = None,
+                let code = match KeyKind::from(s) {
+                    KeyKind::Mod(kb_modifier) => {
+                        map.modifiers |= kb_modifier;
+                        return Ok(map);
+                    }
+                    KeyKind::KeySym(kb_code) => KbCodeType::Sym(kb_code),
+                    KeyKind::KeyCode(kb_code) => KbCodeType::Code(kb_code),
+                    KeyKind::Unknown => return Err(MapError::UnknownKey(s.to_string())),
+                };
+                if let Some(key) = map.key.replace(s.to_string()) {
+                    return Err(MapError::MultipleKeys(key, s.to_string()));
                 }
-                map
-            });
+                map.code = Some(code);
+                Ok(map)
+            })?;
         event.build()
     }
 }
 
+impl Map {
+    /// Parses a tap keymap, which is a single modifier like `<super>` or `<lalt>`.
+    pub fn tap(value: &str) -> Result<Self, MapError> {
+        let s = value.trim();
+        match KeyKind::from(s) {
+            KeyKind::Mod(modifier) => Ok(Map {
+                modifiers: Modifier::default(),
+                code: KbCodeType::Tap(modifier),
+            }),
+            KeyKind::Unknown if !s.contains('+') => Err(MapError::UnknownKey(s.to_string())),
+            _ => Err(MapError::NotAModifier(s.to_string())),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct MapBuilder {
     pub modifiers: Modifier,
     pub code: Option<KbCodeType>,
+    /// The key as written in the config, for error messages.
+    pub key: Option<String>,
 }
 
 impl MapBuilder {
-    pub fn build(mut self) -> Result<Map, ()> {
+    pub fn build(mut self) -> Result<Map, MapError> {
         self.code.take().map_or_else(
-            || Err(()),
+            || Err(MapError::NoKey),
             |code| {
                 Ok(Map {
                     modifiers: self.modifiers,
@@ -108,8 +312,8 @@ impl MapBuilder {
 }
 
 #[derive(Default, Clone)]
-pub struct KeyMaps(pub Arc<RwLock<Vec<KeyMap>>>);
-crate::deref!(KeyMaps => Arc<RwLock<Vec<KeyMap>>>);
+pub struct KeyMaps(pub Arc<RwLock<Vec<Rc<KeyMap>>>>);
+crate::deref!(KeyMaps => Arc<RwLock<Vec<Rc<KeyMap>>>>);
 
 impl KeyMaps {
     pub fn print_maps(&self) {
@@ -154,3 +358,77 @@ impl KeyMaps {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(s: &str) -> Map {
+        Map::try_from(&s.to_string()).unwrap_or_else(|e| panic!("{s}: {e}"))
+    }
+
+    fn error(s: &str) -> MapError {
+        match Map::try_from(&s.to_string()) {
+            Ok(_) => panic!("{s} should be invalid"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn equivalent_spellings_are_equal() {
+        let same = [
+            ("<ctrl>+<shift>+a", "<shift>+<ctrl>+a"),
+            ("<super>+a", "<SUPER> + A"),
+            ("<super>+<ctrl>+a", "<super>+<ctrl>+<ctrl>+a"),
+            ("<alt>+!", "<alt>+<exclam>"),
+            ("<super>+<plus>", "<super>+<Plus>"),
+            ("<super>+<kp1>", "<super>+<KP1>"),
+            ("<super>+[38]", "[38]+<super>"),
+        ];
+        for (a, b) in same {
+            assert!(map(a) == map(b), "{a} == {b}");
+        }
+    }
+
+    #[test]
+    fn different_keymaps_are_not_equal() {
+        let different = [
+            ("<super>+a", "<super>+b"),
+            ("<super>+a", "<super>+<shift>+a"),
+            ("<super>+a", "<lsuper>+a"),
+            ("<lsuper>+a", "<rsuper>+a"),
+            ("<super>+a", "<super>+[38]"),
+            ("<super>+1", "<super>+<kp1>"),
+        ];
+        for (a, b) in different {
+            assert!(map(a) != map(b), "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn invalid_keymaps_are_rejected() {
+        assert!(matches!(error("<super>+<foo>"), MapError::UnknownKey(key) if key == "<foo>"));
+        assert!(matches!(error("<super>+ab"), MapError::UnknownKey(key) if key == "ab"));
+        assert!(matches!(error("<super>+<shift>"), MapError::NoKey));
+        assert!(matches!(
+            error("<super>+a+b"),
+            MapError::MultipleKeys(a, b) if a == "a" && b == "b"
+        ));
+    }
+
+    #[test]
+    fn taps_are_single_modifiers() {
+        for s in ["<super>", "<lalt>", "<RShift>", "<mod3>"] {
+            assert!(Map::tap(s).is_ok(), "{s}");
+        }
+        assert!(Map::tap("<super>").ok() == Map::tap(" <Super> ").ok());
+        assert!(Map::tap("<super>").ok() != Map::tap("<lsuper>").ok());
+
+        assert!(matches!(Map::tap("a"), Err(MapError::NotAModifier(_))));
+        assert!(matches!(
+            Map::tap("<super>+<alt>"),
+            Err(MapError::NotAModifier(_))
+        ));
+        assert!(matches!(Map::tap("<foo>"), Err(MapError::UnknownKey(_))));
+    }
+}
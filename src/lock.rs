@@ -0,0 +1,99 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{AppError, AppResult},
+    logger::log,
+};
+
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lock file that makes sure only one daemon grabs the keys of a display.
+///
+/// The file lives in `$XDG_RUNTIME_DIR` and holds the pid of the running instance, the lock itself
+/// is an `flock` so it is released by the kernel when the daemon dies.
+pub struct InstanceLock {
+    file: File,
+}
+
+impl InstanceLock {
+    pub fn acquire(replace: bool) -> AppResult<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path())?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+            Err(TryLockError::WouldBlock) => {
+                let pid = read_pid(&file);
+                match (replace, pid) {
+                    (true, Some(pid)) => replace_instance(&file, pid)?,
+                    _ => return Err(AppError::AlreadyRunning(pid)),
+                }
+            }
+        }
+
+        let mut lock = Self { file };
+        lock.write_pid()?;
+        Ok(lock)
+    }
+
+    fn write_pid(&mut self) -> AppResult<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        write!(self.file, "{}", std::process::id())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // The file is not removed, a process waiting on the lock would otherwise end up holding
+        // a lock on an unlinked file while a new instance creates a fresh one.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Asks the running instance to exit and waits until it released the lock.
+fn replace_instance(file: &File, pid: i32) -> AppResult<()> {
+    log(format!("Replacing running instance (pid {pid})"));
+    unsafe {
+        libc::kill(pid, libc::SIGTERM);
+    }
+
+    let start = Instant::now();
+    while start.elapsed() < REPLACE_TIMEOUT {
+        match file.try_lock() {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+            Err(TryLockError::WouldBlock) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+    Err(AppError::ReplaceTimeout(pid))
+}
+
+fn read_pid(mut file: &File) -> Option<i32> {
+    let mut pid = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+fn lock_path() -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+
+    // Every display has its own set of grabs, so we key the lock on it.
+    let display = std::env::var("DISPLAY").unwrap_or_default().replace('/', "_");
+    dir.join(format!("crusty-keys{display}.pid"))
+}
@@ -0,0 +1,107 @@
+use mlua::{Function, Lua, Table};
+
+use crate::{
+    error::AppResult,
+    i3::{
+        self, EVENTS, GET_MARKS, GET_OUTPUTS, GET_TREE, GET_VERSION, GET_WORKSPACES, I3Events,
+        RUN_COMMAND, command_errors, select_backend,
+    },
+    logger::log,
+    lua::{I3, LuaEngine},
+};
+
+impl LuaEngine {
+    pub(super) fn create_i3_table(&self) -> AppResult<Table> {
+        let i3_table = self.lua.create_table()?;
+        i3_table.set("on", self.i3_on_func()?)?;
+        i3_table.set("get_tree", self.i3_query_func(GET_TREE)?)?;
+        i3_table.set("get_workspaces", self.i3_query_func(GET_WORKSPACES)?)?;
+        i3_table.set("get_outputs", self.i3_query_func(GET_OUTPUTS)?)?;
+        i3_table.set("get_marks", self.i3_query_func(GET_MARKS)?)?;
+        i3_table.set("get_version", self.i3_query_func(GET_VERSION)?)?;
+        i3_table.set("focused", self.i3_focused_func()?)?;
+        Ok(i3_table)
+    }
+
+    /// Event source delivering i3 events to the callbacks registered with `ck.i3.on`.
+    pub(crate) fn i3_events(&self) -> I3Events {
+        I3Events::new(
+            self.lua.clone(),
+            self.i3_handlers.clone(),
+            select_backend(&self.settings),
+        )
+    }
+
+    fn i3_on_func(&self) -> AppResult<Function> {
+        let handlers = self.i3_handlers.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, (event, cb): (String, Function)| {
+                if !EVENTS.contains(&event.as_str()) {
+                    return Err(mlua::Error::runtime(format!(
+                        "Unknown i3 event \"{event}\", expected one of: {}",
+                        EVENTS.join(", ")
+                    )));
+                }
+
+                if let Ok(mut handlers) = handlers.write() {
+                    handlers.entry(event).or_default().push(cb);
+                }
+                Ok(())
+            })?;
+        Ok(f)
+    }
+
+    /// Returns the reply to `message_type` as a table, or nil if i3 is not reachable.
+    fn i3_query_func(&self, message_type: u32) -> AppResult<Function> {
+        let i3 = self.i3.clone();
+        let f = self.lua.create_function(move |lua: &Lua, ()| {
+            query(&i3, message_type)
+                .map(|reply| i3::to_lua(lua, &reply))
+                .transpose()
+        })?;
+        Ok(f)
+    }
+
+    /// Returns the focused container of the tree.
+    fn i3_focused_func(&self) -> AppResult<Function> {
+        let i3 = self.i3.clone();
+        let f = self.lua.create_function(move |lua: &Lua, ()| {
+            query(&i3, GET_TREE)
+                .and_then(|tree| i3::find_focused(&tree).map(|node| i3::to_lua(lua, node)))
+                .transpose()
+        })?;
+        Ok(f)
+    }
+}
+
+fn query(i3: &I3, message_type: u32) -> Option<serde_json::Value> {
+    let mut i3 = i3.write().ok()?;
+    match i3.request(message_type, "") {
+        Ok(reply) => Some(reply),
+        Err(e) => {
+            log(format!("i3 request failed: {e}"));
+            None
+        }
+    }
+}
+
+/// The current tree, or None if i3 is not running. Unlike `query` this does not log, it is used
+/// to find out whether to fall back to X.
+pub(super) fn tree(i3: &I3) -> Option<serde_json::Value> {
+    i3.write().ok()?.request(GET_TREE, "").ok()
+}
+
+/// Runs an i3 command, failures are only logged.
+pub(super) fn command(i3: &I3, args: &str) {
+    let errors = match i3.write() {
+        Ok(mut i3) => match i3.request(RUN_COMMAND, args) {
+            Ok(reply) => command_errors(&reply),
+            Err(e) => vec![e.to_string()],
+        },
+        Err(_) => return,
+    };
+    if !errors.is_empty() {
+        log(format!("i3 command \"{args}\" failed: {}", errors.join(", ")));
+    }
+}
@@ -1,34 +1,76 @@
+mod i3;
+mod proc;
+mod scratchpad;
+mod timer;
+mod wm;
+mod x11;
+
 use std::{
     collections::HashMap,
+    rc::Rc,
     sync::{Arc, RwLock},
 };
 
-use i3ipc::I3Connection;
 use mlua::{Function, Lua, Table};
 
 use crate::{
-    AppArgs, KeyMap,
+    AppArgs, KeyMap, Mode,
+    check::{self, Problems},
     config::Config,
-    error::AppResult,
-    key_maps::{KeyMapOptions, KeyMaps, Map},
+    error::{AppError, AppResult},
+    ewmh,
+    i3::{I3Conn, I3Handlers, RUN_COMMAND, command_errors, windows},
+    logger::log,
+    key_maps::{KeyMapOptions, KeyMaps, Layout, Map},
+    lua::scratchpad::Scratchpads,
+    proc::Processes,
+    settings::Settings,
+    timer::Timers,
+    lua::x11::WindowFilter,
+    xconn::{Display, XConn},
 };
 
-type I3 = Arc<Option<RwLock<I3Connection>>>;
+pub(crate) type I3 = Arc<RwLock<I3Conn>>;
 
 pub(crate) struct LuaEngine {
     lua: mlua::Lua,
     i3: I3,
+    i3_handlers: I3Handlers,
     pub(crate) keymaps: KeyMaps,
+    pub(crate) procs: Processes,
+    pub(crate) timers: Timers,
+    scratchpads: Scratchpads,
+    pub(crate) settings: Settings,
+    pub(crate) problems: Problems,
+    pub(crate) x: XConn,
     config: Config,
 }
 
 impl LuaEngine {
     pub fn new(args: &AppArgs) -> AppResult<Self> {
+        let config = match Config::try_from(args) {
+            Ok(config) => config,
+            Err(()) => match &args.config {
+                // Checking the default config instead would hide the mistake
+                Some(path) if matches!(args.mode, Mode::Check) => {
+                    return Err(AppError::InvalidConfig(path.clone()));
+                }
+                _ => Config::new()?,
+            },
+        };
+
         Self {
             lua: mlua::Lua::new(),
-            i3: Arc::new(I3Connection::connect().ok().map(RwLock::new)),
+            i3: I3::default(),
+            i3_handlers: I3Handlers::default(),
             keymaps: KeyMaps::default(),
-            config: Config::try_from(args).unwrap_or(Config::new()?),
+            procs: Processes::default(),
+            timers: Timers::default(),
+            scratchpads: Scratchpads::default(),
+            settings: Settings::default(),
+            problems: Problems::new(matches!(args.mode, Mode::Check | Mode::Inspect)),
+            x: XConn::default(),
+            config,
         }
         .load()
     }
@@ -47,16 +89,54 @@ impl LuaEngine {
             .exec()?;
 
         // Loading the main config file into the engine.
-        self.lua.load(&self.config.cfg).exec()?;
+        self.lua
+            .load(&self.config.cfg)
+            .set_name(format!("@{}", self.config.path.display()))
+            .exec()?;
+
+        self.settings = self
+            .lua
+            .globals()
+            .get::<Table>("ck")?
+            .get::<Table>("settings")
+            .map(Settings::from)
+            .unwrap_or_default();
+
+        if let Ok(mut i3) = self.i3.write() {
+            i3.configure(&self.settings);
+        }
         Ok(self)
     }
+
+    /// Runs the `ck.on_exit` hook and closes the i3 connection. `reason` is passed on to the hook
+    /// and is either "exit" or "restart".
+    pub fn shutdown(&self, reason: &str) {
+        if let Ok(ck) = self.lua.globals().get::<Table>("ck")
+            && let Ok(on_exit) = ck.get::<Function>("on_exit")
+            && let Err(e) = on_exit.call::<()>(reason)
+        {
+            log(format!("ck.on_exit failed: {e}"));
+        }
+
+        if let Ok(mut i3) = self.i3.write() {
+            i3.disconnect();
+        }
+    }
+
     fn set_globals(&mut self) -> crate::error::AppResult<()> {
         // main table
         let ck = self.lua.create_table()?;
 
         // keymap
         ck.set("keymap", self.create_keymap_table()?)?;
+        ck.set("settings", self.lua.create_table()?)?;
         ck.set("util", self.create_util_table()?)?;
+        ck.set("i3", self.create_i3_table()?)?;
+        ck.set("proc", self.create_proc_table()?)?;
+        ck.set("scratchpad", self.create_scratchpad_table()?)?;
+        ck.set("timer", self.create_timer_table()?)?;
+        ck.set("wm", self.create_wm_table()?)?;
+        ck.set("x11", self.create_x11_table()?)?;
 
         let _ = self.lua.globals().set("ck", ck);
         Ok(())
@@ -72,6 +152,7 @@ impl LuaEngine {
         let util_table = self.lua.create_table()?;
         util_table.set("i3", self.i3_msg_func()?)?;
         util_table.set("run", self.run_func()?)?;
+        util_table.set("run_or_raise", self.run_or_raise_func()?)?;
         Ok(util_table)
     }
 }
@@ -79,47 +160,139 @@ impl LuaEngine {
 // Global Lua functions impl
 impl LuaEngine {
     fn run_func(&self) -> Result<Function, mlua::Error> {
+        let procs = self.procs.clone();
+        let problems = self.problems.clone();
         let f = self.lua.create_function(
-            move |_lua: &Lua, (args, options): (String, Option<Table>)| {
-                let opt = RunOptions::from(options);
-                let args = args.split_whitespace().collect::<Vec<&str>>();
+            move |lua: &Lua, (args, options): (String, Option<Table>)| {
+                Ok(run(lua, &problems, &args, options).map(|pid| procs.track(pid, None)))
+            },
+        )?;
 
-                if opt.in_terminal {
-                    cmd::spawn_in_terminal(args, opt);
-                } else if let Some(cmd) = args.first() {
-                    cmd::spawn(cmd, args[1..].to_vec(), opt);
+        Ok(f)
+    }
+
+    /// Focuses a window matching the filter table, or runs the command when there is none. When
+    /// a matching window already has the focus the next match is focused, so repeated calls cycle
+    /// through all matches. Windows are looked up in the i3 tree, or through EWMH if i3 is not
+    /// running. Returns the process handle when the command was started.
+    fn run_or_raise_func(&self) -> AppResult<Function> {
+        let i3 = self.i3.clone();
+        let x = self.x.clone();
+        let procs = self.procs.clone();
+        let problems = self.problems.clone();
+        let f = self.lua.create_function(
+            move |lua: &Lua, (filter, args, options): (Table, String, Option<Table>)| {
+                let filter = raise_filter(filter)?;
+                // Raising needs X or i3, the check only looks at the command
+                if problems.checking() {
+                    return Ok(
+                        run(lua, &problems, &args, options).map(|pid| procs.track(pid, None))
+                    );
                 }
-                Ok(())
+
+                let raised = match i3::tree(&i3) {
+                    Some(tree) => raise_i3(&i3, &tree, &filter),
+                    None => raise_x11(&*wm::display(&x)?, &filter),
+                };
+                if raised {
+                    return Ok(None);
+                }
+
+                Ok(run(lua, &problems, &args, options).map(|pid| procs.track(pid, None)))
             },
         )?;
-
         Ok(f)
     }
 
+    /// Runs an i3 command and returns whether it succeeded together with the error messages of
+    /// all commands that failed.
     fn i3_msg_func(&self) -> AppResult<Function> {
         let i3 = self.i3.clone();
-        let f = self.lua.create_function(move |_lua: &Lua, args: String| {
-            if let Some(i3_lock) = i3.as_ref()
-                && let Ok(mut i3) = i3_lock.write()
-            {
-                let _ = i3.run_command(&args);
-            }
-            Ok(())
-        })?;
+        let problems = self.problems.clone();
+        let f = self.lua.create_function(
+            move |_lua: &Lua, (args, options): (String, Option<Table>)| {
+                let opt = I3Options::from(options);
+                // The config is only loaded to check it, i3 should not run anything
+                if problems.checking() {
+                    return Ok((true, Vec::new()));
+                }
+
+                let errors = match i3.write() {
+                    Ok(mut i3) => match i3.request(RUN_COMMAND, &args) {
+                        Ok(reply) => command_errors(&reply),
+                        Err(e) => vec![e.to_string()],
+                    },
+                    Err(_) => vec![AppError::WriteLockError.to_string()],
+                };
+
+                if !errors.is_empty() {
+                    let msg = format!("i3 command \"{args}\" failed: {}", errors.join(", "));
+                    log(&msg);
+                    if opt.strict {
+                        return Err(mlua::Error::runtime(msg));
+                    }
+                }
+                Ok((errors.is_empty(), errors))
+            },
+        )?;
         Ok(f)
     }
 
     fn keymap_set_func(&self) -> AppResult<Function> {
         let keymaps = self.keymaps.clone();
+        let problems = self.problems.clone();
         let f = self.lua.create_function(
-            move |_lua: &Lua, (keymap, cb, desc): (String, Function, Option<Table>)| {
-                if let (Ok(map), Ok(mut maps)) = (Map::try_from(&keymap), keymaps.write()) {
-                    maps.push(KeyMap {
+            move |lua: &Lua, (keymap, cb, desc): (String, Function, Option<Table>)| {
+                problems.check_options(lua, desc.as_ref(), &KeyMapOptions::KEYS);
+                if let Some(layout) = desc.as_ref().and_then(|d| d.get::<String>("layout").ok())
+                    && Layout::try_from(layout.as_str()).is_err()
+                {
+                    problems.report(
+                        check::location(lua),
+                        format!(
+                            "unknown layout \"{layout}\", expected one of {}",
+                            Layout::NAMES.join(", ")
+                        ),
+                    );
+                }
+                let options = desc.map(KeyMapOptions::from).unwrap_or_default();
+                let map = if options.tap {
+                    Map::tap(&keymap)
+                } else {
+                    Map::try_from(&keymap)
+                };
+                let map = match map {
+                    Ok(map) => map,
+                    Err(e) => {
+                        problems.report(
+                            check::location(lua),
+                            format!("invalid keymap \"{keymap}\": {e}"),
+                        );
+                        return Ok(());
+                    }
+                };
+                let location = check::location(lua);
+
+                if let Ok(mut maps) = keymaps.write() {
+                    if let Some(index) = maps.iter().position(|other| other.map == map) {
+                        let earlier = maps.remove(index);
+                        if !options.r#override {
+                            problems.report(
+                                location.clone(),
+                                format!(
+                                    "keymap \"{keymap}\" is already set at {} as \"{}\", the earlier keymap is replaced (set override = true to silence this)",
+                                    earlier.location, earlier.s
+                                ),
+                            );
+                        }
+                    }
+                    maps.push(Rc::new(KeyMap {
                         map,
                         cb,
                         s: keymap,
-                        options: desc.map(KeyMapOptions::from).unwrap_or_default(),
-                    });
+                        options,
+                        location,
+                    }));
                 }
                 Ok(())
             },
@@ -128,12 +301,75 @@ impl LuaEngine {
     }
 }
 
+/// The windows `ck.util.run_or_raise` raises, an empty filter would match any window.
+fn raise_filter(filter: Table) -> mlua::Result<WindowFilter> {
+    let filter = WindowFilter::from(Some(filter));
+    if filter.is_empty() {
+        return Err(mlua::Error::runtime(
+            "run_or_raise needs a class, instance or title to find its window",
+        ));
+    }
+    Ok(filter)
+}
+
+/// Focuses the next window of the i3 tree matching `filter`, returns false if there is none.
+fn raise_i3(i3: &I3, tree: &serde_json::Value, filter: &WindowFilter) -> bool {
+    let windows = windows(tree)
+        .into_iter()
+        .filter(|node| filter.matches_node(node))
+        .collect::<Vec<_>>();
+
+    let Some(next) = next_match(&windows, |node| node["focused"].as_bool() == Some(true)) else {
+        return false;
+    };
+
+    i3::command(i3, &format!("[con_id={}] focus", next["id"]));
+    true
+}
+
+/// Activates the next window matching `filter` through EWMH, returns false if there is none.
+fn raise_x11(display: &Display, filter: &WindowFilter) -> bool {
+    let clients = x11::find(display, filter);
+    let active = ewmh::active_window(display).or_else(|| display.input_focus());
+
+    match next_match(&clients, |client| Some(client.id) == active) {
+        Some(client) => {
+            ewmh::activate(display, client.id);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs a command line for `ck.util.run` and the like, returning the pid. `crusty-keys check`
+/// only makes sure the executable exists.
+fn run(lua: &Lua, problems: &Problems, args: &str, options: Option<Table>) -> Option<u32> {
+    problems.check_options(lua, options.as_ref(), &RunOptions::KEYS);
+    if problems.checking() {
+        problems.check_command(lua, args);
+        return None;
+    }
+    cmd::run(args, RunOptions::from(options))
+}
+
+/// The match after the focused one, or the first match if none of them has the focus.
+fn next_match<T>(matches: &[T], is_focused: impl Fn(&T) -> bool) -> Option<&T> {
+    match matches.iter().position(is_focused) {
+        Some(index) => matches.get((index + 1) % matches.len()),
+        None => matches.first(),
+    }
+}
+
 #[derive(Default, Debug)]
 struct RunOptions {
     env: HashMap<String, String>,
     in_terminal: bool,
 }
 
+impl RunOptions {
+    const KEYS: [&str; 2] = ["env", "in_terminal"];
+}
+
 impl From<Option<Table>> for RunOptions {
     fn from(mut value: Option<Table>) -> Self {
         value.take().map_or_else(Self::default, |table| Self {
@@ -145,6 +381,18 @@ impl From<Option<Table>> for RunOptions {
     }
 }
 
+#[derive(Default, Debug)]
+struct I3Options {
+    strict: bool,
+}
+
+impl From<Option<Table>> for I3Options {
+    fn from(mut value: Option<Table>) -> Self {
+        value.take().map_or_else(Self::default, |table| Self {
+            strict: table.get::<bool>("strict").ok().unwrap_or_default(),
+        })
+    }
+}
 
 mod cmd {
     use crate::{logger::log, lua::RunOptions};
@@ -153,16 +401,27 @@ mod cmd {
         process::{Command, Stdio},
     };
 
-    pub fn spawn_in_terminal(args: Vec<&str>, opt: RunOptions) {
-        if let Some((_, term)) =
-            std::env::vars().find(|(k, v)| k.as_str() == "TERM" && !v.is_empty())
-        {
-            let args = ["-e"].into_iter().chain(args).collect();
-            spawn(&term, args, opt);
+    /// Spawns `args` as a whitespace separated command line and returns the pid of the child.
+    pub fn run(args: &str, opt: RunOptions) -> Option<u32> {
+        let args = args.split_whitespace().collect::<Vec<&str>>();
+
+        if opt.in_terminal {
+            spawn_in_terminal(args, opt)
+        } else if let Some(cmd) = args.first() {
+            spawn(cmd, args[1..].to_vec(), opt)
+        } else {
+            None
         }
     }
 
-    pub fn spawn(cmd: &str, args: Vec<&str>, opt: RunOptions) {
+    pub fn spawn_in_terminal(args: Vec<&str>, opt: RunOptions) -> Option<u32> {
+        let (_, term) =
+            std::env::vars().find(|(k, v)| k.as_str() == "TERM" && !v.is_empty())?;
+        let args = ["-e"].into_iter().chain(args).collect();
+        spawn(&term, args, opt)
+    }
+
+    pub fn spawn(cmd: &str, args: Vec<&str>, opt: RunOptions) -> Option<u32> {
         let mut e = Command::new(cmd);
         e.args(&args)
             .stdin(Stdio::null())
@@ -171,8 +430,30 @@ mod cmd {
             .process_group(0)
             .envs(opt.env);
 
-        if let Err(e) = e.spawn() {
-            log(e.to_string().as_str());
+        match e.spawn() {
+            // Dropping the `Child` neither kills nor waits for it, the reaper collects it.
+            Ok(child) => Some(child.id()),
+            Err(e) => {
+                log(e.to_string().as_str());
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raise_filter_needs_something_to_match() -> mlua::Result<()> {
+        let lua = Lua::new();
+        for filter in ["{}", r#"{ cmd = "firefox" }"#] {
+            assert!(raise_filter(lua.load(filter).eval()?).is_err(), "{filter}");
         }
+        for filter in [r#"{ class = "firefox" }"#, r#"{ instance = "x" }"#, r#"{ title = "x" }"#] {
+            assert!(raise_filter(lua.load(filter).eval()?).is_ok(), "{filter}");
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,32 @@
+use mlua::{Function, Lua, Table};
+
+use crate::{
+    error::AppResult,
+    lua::{LuaEngine, run},
+};
+
+impl LuaEngine {
+    pub(super) fn create_proc_table(&self) -> AppResult<Table> {
+        let proc_table = self.lua.create_table()?;
+        proc_table.set("toggle", self.proc_toggle_func()?)?;
+        Ok(proc_table)
+    }
+
+    /// Starts `cmd` under `name` if it is not running yet, otherwise the running instance is
+    /// terminated. Returns the process handle when a new process was started.
+    fn proc_toggle_func(&self) -> AppResult<Function> {
+        let procs = self.procs.clone();
+        let problems = self.problems.clone();
+        let f = self.lua.create_function(
+            move |lua: &Lua, (name, args, options): (String, String, Option<Table>)| {
+                if let Some(process) = procs.find(&name) {
+                    process.kill(libc::SIGTERM);
+                    return Ok(None);
+                }
+
+                Ok(run(lua, &problems, &args, options).map(|pid| procs.track(pid, Some(name))))
+            },
+        )?;
+        Ok(f)
+    }
+}
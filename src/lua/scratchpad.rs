@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, Lua, Table, Value};
+use x11_dl::xlib::Window;
+
+use crate::{
+    check::Problems,
+    error::{AppError, AppResult},
+    ewmh,
+    i3::{GET_WORKSPACES, find_workspace, windows},
+    logger::log,
+    lua::{I3, LuaEngine, RunOptions, cmd, i3, wm, x11, x11::WindowFilter},
+    proc::Processes,
+    timer::Timers,
+    xconn::{Display, Geometry, XConn},
+};
+
+/// How often to look for the window of a scratchpad program that was just started.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for that window before giving up.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// i3 keeps hidden scratchpad windows on this workspace, sway does the same.
+const SCRATCH_WORKSPACE: &str = "__i3_scratch";
+
+pub(crate) struct Scratchpad {
+    cmd: Vec<String>,
+    filter: WindowFilter,
+    geometry: Option<GeometrySpec>,
+    /// The con_id of the window under i3, its X window id otherwise.
+    window: Option<u64>,
+    /// Timer looking for the window after the program was started.
+    waiting: Option<u64>,
+}
+
+impl TryFrom<Table> for Scratchpad {
+    type Error = mlua::Error;
+
+    fn try_from(table: Table) -> Result<Self, Self::Error> {
+        let cmd = match table.get::<Value>("cmd")? {
+            Value::String(s) => s
+                .to_str()?
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            Value::Table(t) => t.sequence_values().collect::<mlua::Result<Vec<String>>>()?,
+            _ => vec![],
+        };
+        if cmd.is_empty() {
+            return Err(mlua::Error::runtime("scratchpad needs a cmd"));
+        }
+
+        let filter = WindowFilter::from(Some(table.clone()));
+        if filter.is_empty() {
+            return Err(mlua::Error::runtime(
+                "scratchpad needs a class, instance or title to find its window",
+            ));
+        }
+
+        let geometry = table
+            .get::<Option<String>>("geometry")?
+            .map(|s| {
+                GeometrySpec::parse(&s)
+                    .ok_or_else(|| mlua::Error::runtime(format!("Invalid geometry \"{s}\"")))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            cmd,
+            filter,
+            geometry,
+            window: None,
+            waiting: None,
+        })
+    }
+}
+
+/// Scratchpads defined through `ck.scratchpad.define`, keyed by name.
+#[derive(Default, Clone)]
+pub(crate) struct Scratchpads(pub Arc<RwLock<HashMap<String, Scratchpad>>>);
+crate::deref!(Scratchpads => Arc<RwLock<HashMap<String, Scratchpad>>>);
+
+impl LuaEngine {
+    pub(super) fn create_scratchpad_table(&self) -> AppResult<Table> {
+        let scratchpad_table = self.lua.create_table()?;
+        scratchpad_table.set("define", self.scratchpad_define_func()?)?;
+        scratchpad_table.set("toggle", self.scratchpad_toggle_func()?)?;
+        Ok(scratchpad_table)
+    }
+
+    fn scratchpad_define_func(&self) -> AppResult<Function> {
+        let scratchpads = self.scratchpads.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, (name, options): (String, Table)| {
+                let scratchpad = Scratchpad::try_from(options)?;
+                if let Ok(mut scratchpads) = scratchpads.write() {
+                    scratchpads.insert(name, scratchpad);
+                }
+                Ok(())
+            })?;
+        Ok(f)
+    }
+
+    /// Shows the scratchpad window, starting its program when it has none yet. A visible window
+    /// is hidden when it has the focus and focused otherwise.
+    fn scratchpad_toggle_func(&self) -> AppResult<Function> {
+        let toggler = Toggler {
+            scratchpads: self.scratchpads.clone(),
+            i3: self.i3.clone(),
+            x: self.x.clone(),
+            timers: self.timers.clone(),
+            procs: self.procs.clone(),
+            problems: self.problems.clone(),
+        };
+        let f = self
+            .lua
+            .create_function(move |lua: &Lua, name: String| toggler.toggle(lua, &name))?;
+        Ok(f)
+    }
+}
+
+/// Everything needed to toggle a scratchpad, windows are handled through i3 when it is running
+/// and through EWMH otherwise.
+#[derive(Clone)]
+struct Toggler {
+    scratchpads: Scratchpads,
+    i3: I3,
+    x: XConn,
+    timers: Timers,
+    procs: Processes,
+    problems: Problems,
+}
+
+impl Toggler {
+    fn toggle(&self, lua: &Lua, name: &str) -> mlua::Result<()> {
+        let mut scratchpads = self
+            .scratchpads
+            .write()
+            .map_err(|_| mlua::Error::external(AppError::WriteLockError))?;
+        let scratchpad = scratchpads
+            .get_mut(name)
+            .ok_or_else(|| mlua::Error::runtime(format!("Unknown scratchpad \"{name}\"")))?;
+        // Showing the window needs X or i3, the check only looks at the command
+        if self.problems.checking() {
+            self.problems.check_command(lua, &scratchpad.cmd.join(" "));
+            return Ok(());
+        }
+
+        let found = match i3::tree(&self.i3) {
+            Some(tree) => self.toggle_i3(&tree, scratchpad),
+            None => self.toggle_x11(&*wm::display(&self.x)?, scratchpad),
+        };
+        if !found && scratchpad.waiting.is_none() {
+            self.start(lua, name, scratchpad)?;
+        }
+        Ok(())
+    }
+
+    fn toggle_i3(&self, tree: &serde_json::Value, scratchpad: &mut Scratchpad) -> bool {
+        let windows = windows(tree);
+        let node = windows
+            .iter()
+            .find(|node| scratchpad.window.is_some() && node["id"].as_u64() == scratchpad.window)
+            .or_else(|| windows.iter().find(|node| scratchpad.filter.matches_node(node)));
+        let Some((node, id)) = node.and_then(|node| Some((node, node["id"].as_u64()?))) else {
+            return false;
+        };
+        scratchpad.window = Some(id);
+
+        let hidden = find_workspace(tree, id)
+            .is_some_and(|workspace| workspace["name"].as_str() == Some(SCRATCH_WORKSPACE));
+        if hidden {
+            self.show_i3(id, scratchpad.geometry.as_ref());
+        } else if node["focused"].as_bool() == Some(true) {
+            i3::command(&self.i3, &format!("[con_id={id}] move scratchpad"));
+        } else {
+            i3::command(&self.i3, &format!("[con_id={id}] focus"));
+        }
+        true
+    }
+
+    fn show_i3(&self, id: u64, geometry: Option<&GeometrySpec>) {
+        let mut args = format!("[con_id={id}] scratchpad show");
+        if let Some(geometry) = geometry
+            && let Some(screen) = self.i3_screen()
+        {
+            let g = geometry.resolve(screen);
+            args.push_str(&format!(
+                ", resize set {} px {} px, move absolute position {} px {} px",
+                g.width, g.height, g.x, g.y
+            ));
+        }
+        i3::command(&self.i3, &args);
+    }
+
+    /// The area of the focused workspace.
+    fn i3_screen(&self) -> Option<Geometry> {
+        let workspaces = self.i3.write().ok()?.request(GET_WORKSPACES, "").ok()?;
+        let rect = &workspaces
+            .as_array()?
+            .iter()
+            .find(|workspace| workspace["focused"].as_bool() == Some(true))?["rect"];
+
+        Some(Geometry {
+            x: rect["x"].as_i64()? as i32,
+            y: rect["y"].as_i64()? as i32,
+            width: rect["width"].as_u64()? as u32,
+            height: rect["height"].as_u64()? as u32,
+        })
+    }
+
+    fn toggle_x11(&self, display: &Display, scratchpad: &mut Scratchpad) -> bool {
+        // A withdrawn window is not in the client list anymore but is back on the root window
+        let known = scratchpad.window.filter(|window| {
+            display.top_level_windows().contains(window)
+                || ewmh::client_list(display).contains(window)
+        });
+        let window = known.or_else(|| {
+            x11::find(display, &scratchpad.filter)
+                .first()
+                .map(|client| client.id)
+        });
+        let Some(window) = window else {
+            return false;
+        };
+        scratchpad.window = Some(window);
+
+        if !display.is_viewable(window) {
+            show_x11(display, window, scratchpad.geometry.as_ref());
+        } else if ewmh::active_window(display).or_else(|| display.input_focus()) == Some(window) {
+            display.withdraw(window);
+        } else {
+            ewmh::activate(display, window);
+        }
+        true
+    }
+
+    /// Starts the program of the scratchpad and waits for its window to show up.
+    fn start(&self, lua: &Lua, name: &str, scratchpad: &mut Scratchpad) -> mlua::Result<()> {
+        let Some((cmd, args)) = scratchpad.cmd.split_first() else {
+            return Ok(());
+        };
+        let args = args.iter().map(String::as_str).collect();
+        let Some(pid) = cmd::spawn(cmd, args, RunOptions::default()) else {
+            return Ok(());
+        };
+        self.procs.track(pid, None);
+
+        let toggler = self.clone();
+        let name = name.to_string();
+        let give_up = Instant::now() + WAIT_TIMEOUT;
+        let cb = lua.create_function(move |_lua: &Lua, ()| {
+            toggler.adopt(&name, give_up);
+            Ok(())
+        })?;
+        let handle = self
+            .timers
+            .schedule(POLL_INTERVAL, Some(POLL_INTERVAL), cb);
+        scratchpad.waiting = Some(handle.id());
+        Ok(())
+    }
+
+    /// Looks for the window of a started program, once found it becomes the scratchpad window.
+    fn adopt(&self, name: &str, give_up: Instant) {
+        let Ok(mut scratchpads) = self.scratchpads.write() else {
+            return;
+        };
+        let Some(scratchpad) = scratchpads.get_mut(name) else {
+            return;
+        };
+
+        let found = match i3::tree(&self.i3) {
+            Some(tree) => windows(&tree)
+                .into_iter()
+                .find(|node| scratchpad.filter.matches_node(node))
+                .and_then(|node| node["id"].as_u64())
+                .inspect(|id| {
+                    i3::command(&self.i3, &format!("[con_id={id}] move scratchpad"));
+                    self.show_i3(*id, scratchpad.geometry.as_ref());
+                }),
+            None => self.x.display().ok().and_then(|display| {
+                x11::find(&display, &scratchpad.filter)
+                    .first()
+                    .map(|client| client.id)
+                    .inspect(|window| show_x11(&display, *window, scratchpad.geometry.as_ref()))
+            }),
+        };
+
+        if found.is_none() && Instant::now() < give_up {
+            return;
+        }
+        if found.is_none() {
+            log(format!("No window showed up for scratchpad \"{name}\""));
+        }
+        scratchpad.window = found;
+        if let Some(id) = scratchpad.waiting.take() {
+            self.timers.cancel(id);
+        }
+    }
+}
+
+fn show_x11(display: &Display, window: Window, geometry: Option<&GeometrySpec>) {
+    // Configured before mapping so the window manager places it there right away
+    if let Some(geometry) = geometry {
+        display.move_resize(window, geometry.resolve(work_area(display)));
+    }
+    display.map(window);
+    ewmh::activate(display, window);
+}
+
+/// The work area of the current desktop, or the whole screen if the window manager doesn't set
+/// `_NET_WORKAREA`.
+fn work_area(display: &Display) -> Geometry {
+    let desktop = ewmh::current_desktop(display).unwrap_or_default() as usize;
+    let areas = display.get_longs(display.root, "_NET_WORKAREA");
+
+    match areas.chunks_exact(4).nth(desktop).or(areas.chunks_exact(4).next()) {
+        Some(&[x, y, width, height]) => Geometry {
+            x: x as i32,
+            y: y as i32,
+            width: width as u32,
+            height: height as u32,
+        },
+        _ => display.geometry(display.root).unwrap_or(Geometry {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        }),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Size {
+    Pixels(u32),
+    Percent(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Position {
+    Pixels(i32),
+    Percent(f64),
+    Start,
+    Center,
+    End,
+}
+
+/// Geometry in the form `WxH+X+Y`, sizes are pixels or percentages of the screen. Positions can
+/// also be `left`, `right`, `top`, `bottom` or `center`. The window is centered when the
+/// position is left out.
+#[derive(Debug, Clone, Copy)]
+struct GeometrySpec {
+    width: Size,
+    height: Size,
+    x: Position,
+    y: Position,
+}
+
+impl GeometrySpec {
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('+');
+        let (width, height) = parts.next()?.split_once('x')?;
+        let x = parts.next().map_or(Some(Position::Center), parse_position)?;
+        let y = parts.next().map_or(Some(Position::Center), parse_position)?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            width: parse_size(width)?,
+            height: parse_size(height)?,
+            x,
+            y,
+        })
+    }
+
+    fn resolve(&self, screen: Geometry) -> Geometry {
+        let size = |size: Size, total: u32| match size {
+            Size::Pixels(px) => px,
+            Size::Percent(p) => (total as f64 * p / 100.0) as u32,
+        };
+        let width = size(self.width, screen.width);
+        let height = size(self.height, screen.height);
+
+        let position = |position: Position, start: i32, total: u32, len: u32| match position {
+            Position::Pixels(px) => start + px,
+            Position::Percent(p) => start + (total as f64 * p / 100.0) as i32,
+            Position::Start => start,
+            Position::Center => start + (total as i32 - len as i32) / 2,
+            Position::End => start + total as i32 - len as i32,
+        };
+
+        Geometry {
+            x: position(self.x, screen.x, screen.width, width),
+            y: position(self.y, screen.y, screen.height, height),
+            width,
+            height,
+        }
+    }
+}
+
+fn parse_size(s: &str) -> Option<Size> {
+    match s.strip_suffix('%') {
+        Some(p) => p.parse().ok().map(Size::Percent),
+        None => s.parse().ok().map(Size::Pixels),
+    }
+}
+
+fn parse_position(s: &str) -> Option<Position> {
+    match s {
+        "left" | "top" => Some(Position::Start),
+        "center" => Some(Position::Center),
+        "right" | "bottom" => Some(Position::End),
+        s => match s.strip_suffix('%') {
+            Some(p) => p.parse().ok().map(Position::Percent),
+            None => s.parse().ok().map(Position::Pixels),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 1920x1080 screen right of a 1280 pixels wide one.
+    const SCREEN: Geometry = Geometry {
+        x: 1280,
+        y: 0,
+        width: 1920,
+        height: 1080,
+    };
+
+    fn resolve(spec: &str) -> Option<(i32, i32, u32, u32)> {
+        let geometry = GeometrySpec::parse(spec)?.resolve(SCREEN);
+        Some((geometry.x, geometry.y, geometry.width, geometry.height))
+    }
+
+    #[test]
+    fn geometry_sizes_are_pixels_or_percentages() {
+        assert_eq!(resolve("800x600+0+0"), Some((1280, 0, 800, 600)));
+        assert_eq!(resolve("50%x25%+0+0"), Some((1280, 0, 960, 270)));
+    }
+
+    #[test]
+    fn geometry_is_centered_without_a_position() {
+        assert_eq!(resolve("800x600"), Some((1840, 240, 800, 600)));
+        assert_eq!(resolve("800x600+10"), Some((1290, 240, 800, 600)));
+        assert_eq!(resolve("100%x40%+center+top"), Some((1280, 0, 1920, 432)));
+    }
+
+    #[test]
+    fn geometry_positions_are_relative_to_the_screen() {
+        assert_eq!(resolve("800x600+right+bottom"), Some((2400, 480, 800, 600)));
+        assert_eq!(resolve("800x600+left+50%"), Some((1280, 540, 800, 600)));
+        assert_eq!(resolve("800x600+-10+20"), Some((1270, 20, 800, 600)));
+    }
+
+    #[test]
+    fn invalid_geometries_are_rejected() {
+        for spec in ["", "800", "800x", "x600", "800x600+", "800x600+1+2+3", "ax600", "80%%x1"] {
+            assert!(GeometrySpec::parse(spec).is_none(), "{spec}");
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use mlua::{Function, Lua, MultiValue, Table};
+
+use crate::{error::AppResult, lua::LuaEngine};
+
+impl LuaEngine {
+    pub(super) fn create_timer_table(&self) -> AppResult<Table> {
+        let timer_table = self.lua.create_table()?;
+        timer_table.set("after", self.timer_after_func()?)?;
+        timer_table.set("every", self.timer_every_func()?)?;
+        timer_table.set("debounce", self.timer_debounce_func()?)?;
+        Ok(timer_table)
+    }
+
+    fn timer_after_func(&self) -> AppResult<Function> {
+        let timers = self.timers.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, (ms, cb): (u64, Function)| {
+                Ok(timers.schedule(Duration::from_millis(ms), None, cb))
+            })?;
+        Ok(f)
+    }
+
+    fn timer_every_func(&self) -> AppResult<Function> {
+        let timers = self.timers.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, (ms, cb): (u64, Function)| {
+                // A zero interval would run the callback over and over without ever waiting
+                if ms == 0 {
+                    return Err(mlua::Error::runtime("ck.timer.every needs an interval above 0 ms"));
+                }
+                let interval = Duration::from_millis(ms);
+                Ok(timers.schedule(interval, Some(interval), cb))
+            })?;
+        Ok(f)
+    }
+
+    /// Returns a function that runs `cb` once it has not been called for `ms` milliseconds. The
+    /// arguments of the last call are passed on to `cb`.
+    fn timer_debounce_func(&self) -> AppResult<Function> {
+        let timers = self.timers.clone();
+        let f = self
+            .lua
+            .create_function(move |lua: &Lua, (ms, cb): (u64, Function)| {
+                let timers = timers.clone();
+                let pending: Arc<RwLock<Option<u64>>> = Arc::default();
+
+                lua.create_function(move |_lua: &Lua, args: MultiValue| {
+                    if let Ok(mut pending) = pending.write() {
+                        if let Some(id) = pending.take() {
+                            timers.cancel(id);
+                        }
+                        let handle =
+                            timers.schedule(Duration::from_millis(ms), None, cb.bind(args)?);
+                        *pending = Some(handle.id());
+                    }
+                    Ok(())
+                })
+            })?;
+        Ok(f)
+    }
+}
@@ -0,0 +1,152 @@
+use std::rc::Rc;
+
+use mlua::{Function, Lua, Table};
+use x11_dl::xlib::Window;
+
+use crate::{
+    error::AppResult,
+    ewmh::{self, Client},
+    lua::LuaEngine,
+    xconn::{Display, Geometry, XConn},
+};
+
+impl LuaEngine {
+    pub(super) fn create_wm_table(&self) -> AppResult<Table> {
+        let wm_table = self.lua.create_table()?;
+        wm_table.set("active", self.wm_active_func()?)?;
+        wm_table.set("clients", self.wm_clients_func()?)?;
+        wm_table.set("current_desktop", self.wm_current_desktop_func()?)?;
+        wm_table.set("desktops", self.wm_desktops_func()?)?;
+        wm_table.set("switch_desktop", self.wm_switch_desktop_func()?)?;
+        wm_table.set("move_to_desktop", self.wm_move_to_desktop_func()?)?;
+        wm_table.set("focus", self.wm_window_func(ewmh::activate)?)?;
+        wm_table.set("close", self.wm_window_func(ewmh::close)?)?;
+        wm_table.set(
+            "toggle_fullscreen",
+            self.wm_window_func(|d, w| ewmh::toggle_state(d, w, "FULLSCREEN"))?,
+        )?;
+        wm_table.set(
+            "toggle_above",
+            self.wm_window_func(|d, w| ewmh::toggle_state(d, w, "ABOVE"))?,
+        )?;
+        wm_table.set("move_resize", self.wm_move_resize_func()?)?;
+        Ok(wm_table)
+    }
+
+    /// Returns the active client, or nil if no window has the focus.
+    fn wm_active_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self.lua.create_function(move |_lua: &Lua, ()| {
+            let display = display(&x)?;
+            Ok(ewmh::active_window(&display).map(|id| Client::new(&display, id)))
+        })?;
+        Ok(f)
+    }
+
+    fn wm_clients_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self.lua.create_function(move |_lua: &Lua, ()| {
+            let display = display(&x)?;
+            Ok(ewmh::client_list(&display)
+                .into_iter()
+                .map(|id| Client::new(&display, id))
+                .collect::<Vec<_>>())
+        })?;
+        Ok(f)
+    }
+
+    fn wm_current_desktop_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, ()| Ok(ewmh::current_desktop(&*display(&x)?)))?;
+        Ok(f)
+    }
+
+    fn wm_desktops_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, ()| Ok(ewmh::desktops(&*display(&x)?)))?;
+        Ok(f)
+    }
+
+    fn wm_switch_desktop_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self.lua.create_function(move |_lua: &Lua, desktop: u64| {
+            ewmh::switch_desktop(&*display(&x)?, desktop);
+            Ok(())
+        })?;
+        Ok(f)
+    }
+
+    fn wm_move_to_desktop_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self.lua.create_function(
+            move |_lua: &Lua, (desktop, window): (u64, Option<Window>)| {
+                let display = display(&x)?;
+                let window = target(&display, window);
+                if let Some(window) = window {
+                    ewmh::move_to_desktop(&display, window, desktop);
+                }
+                Ok(window.is_some())
+            },
+        )?;
+        Ok(f)
+    }
+
+    fn wm_move_resize_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f =
+            self.lua.create_function(
+                move |_lua: &Lua,
+                      (x_pos, y_pos, width, height, window): (
+                    i32,
+                    i32,
+                    u32,
+                    u32,
+                    Option<Window>,
+                )| {
+                    let display = display(&x)?;
+                    let window = target(&display, window);
+                    if let Some(window) = window {
+                        let geometry = Geometry {
+                            x: x_pos,
+                            y: y_pos,
+                            width,
+                            height,
+                        };
+                        ewmh::move_resize(&display, window, geometry);
+                    }
+                    Ok(window.is_some())
+                },
+            )?;
+        Ok(f)
+    }
+
+    /// Creates a function running `request` on the given window, or the active window if none is
+    /// given. The function returns whether there was a window to send the request for.
+    fn wm_window_func(&self, request: fn(&Display, Window)) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, window: Option<Window>| {
+                let display = display(&x)?;
+                let window = target(&display, window);
+                if let Some(window) = window {
+                    request(&display, window);
+                }
+                Ok(window.is_some())
+            })?;
+        Ok(f)
+    }
+}
+
+pub(super) fn display(x: &XConn) -> mlua::Result<Rc<Display>> {
+    x.display().map_err(mlua::Error::external)
+}
+
+/// The window a request is for, defaults to the active window.
+fn target(display: &Display, window: Option<Window>) -> Option<Window> {
+    window.or_else(|| ewmh::active_window(display))
+}
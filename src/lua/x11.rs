@@ -0,0 +1,169 @@
+use mlua::{Function, Lua, Table, Value};
+use x11_dl::xlib::Window;
+
+use crate::{
+    error::AppResult,
+    ewmh::{self, Client},
+    lua::{LuaEngine, wm::display},
+    xconn::{Display, Geometry},
+};
+
+impl LuaEngine {
+    pub(super) fn create_x11_table(&self) -> AppResult<Table> {
+        let x11_table = self.lua.create_table()?;
+        x11_table.set("active_window", self.x11_active_window_func()?)?;
+        x11_table.set("find", self.x11_find_func()?)?;
+        x11_table.set("focus", self.x11_window_func(Display::focus)?)?;
+        x11_table.set("raise", self.x11_window_func(Display::raise)?)?;
+        x11_table.set("move_resize", self.x11_move_resize_func()?)?;
+        x11_table.set("set_property", self.x11_set_property_func()?)?;
+        Ok(x11_table)
+    }
+
+    /// Returns the active window, or the window holding the input focus when the window manager
+    /// does not set `_NET_ACTIVE_WINDOW`.
+    fn x11_active_window_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self.lua.create_function(move |_lua: &Lua, ()| {
+            let display = display(&x)?;
+            Ok(ewmh::active_window(&display)
+                .or_else(|| display.input_focus())
+                .map(|id| Client::new(&display, id)))
+        })?;
+        Ok(f)
+    }
+
+    /// Returns all windows matching the filter table.
+    fn x11_find_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, filter: Option<Table>| {
+                let display = display(&x)?;
+                Ok(find(&display, &WindowFilter::from(filter)))
+            })?;
+        Ok(f)
+    }
+
+    fn x11_move_resize_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self.lua.create_function(
+            move |_lua: &Lua,
+                  (window, x_pos, y_pos, width, height): (Window, i32, i32, u32, u32)| {
+                let geometry = Geometry {
+                    x: x_pos,
+                    y: y_pos,
+                    width,
+                    height,
+                };
+                display(&x)?.move_resize(window, geometry);
+                Ok(())
+            },
+        )?;
+        Ok(f)
+    }
+
+    /// Sets a property of a window. Strings are stored as UTF8_STRING, integers and lists of
+    /// integers as CARDINAL.
+    fn x11_set_property_func(&self) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self.lua.create_function(
+            move |lua: &Lua, (window, name, value): (Window, String, Value)| {
+                let display = display(&x)?;
+                match value {
+                    Value::String(s) => display.set_string(window, &name, &s.to_str()?),
+                    Value::Table(_) => {
+                        display.set_longs(window, &name, &lua.unpack::<Vec<u64>>(value)?)
+                    }
+                    value => display.set_longs(window, &name, &[lua.unpack::<u64>(value)?]),
+                }
+                Ok(())
+            },
+        )?;
+        Ok(f)
+    }
+
+    fn x11_window_func(&self, request: fn(&Display, Window)) -> AppResult<Function> {
+        let x = self.x.clone();
+        let f = self
+            .lua
+            .create_function(move |_lua: &Lua, window: Window| {
+                request(&*display(&x)?, window);
+                Ok(())
+            })?;
+        Ok(f)
+    }
+}
+
+/// Managed windows matching `filter`. Without a window manager publishing `_NET_CLIENT_LIST`
+/// the children of the root window are searched instead.
+pub(super) fn find(display: &Display, filter: &WindowFilter) -> Vec<Client> {
+    let mut windows = ewmh::client_list(display);
+    if windows.is_empty() {
+        windows = display.top_level_windows();
+    }
+
+    windows
+        .into_iter()
+        .map(|id| Client::new(display, id))
+        .filter(|client| {
+            filter.matches(
+                client.class.as_deref(),
+                client.instance.as_deref(),
+                client.title.as_deref(),
+            )
+        })
+        .collect()
+}
+
+/// Matches windows on their class, instance and title, all ignoring case. The class and instance
+/// have to be equal, the title only has to contain the given text.
+#[derive(Default, Debug)]
+pub(super) struct WindowFilter {
+    class: Option<String>,
+    instance: Option<String>,
+    title: Option<String>,
+}
+
+impl WindowFilter {
+    pub fn matches(
+        &self,
+        class: Option<&str>,
+        instance: Option<&str>,
+        title: Option<&str>,
+    ) -> bool {
+        let equal = |want: &Option<String>, have: Option<&str>| {
+            want.as_ref()
+                .is_none_or(|want| have.is_some_and(|have| have.eq_ignore_ascii_case(want)))
+        };
+        let title_matches = self.title.as_ref().is_none_or(|want| {
+            title.is_some_and(|have| have.to_lowercase().contains(&want.to_lowercase()))
+        });
+
+        equal(&self.class, class) && equal(&self.instance, instance) && title_matches
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.class.is_none() && self.instance.is_none() && self.title.is_none()
+    }
+
+    /// Matches a window node of the i3 tree.
+    pub fn matches_node(&self, node: &serde_json::Value) -> bool {
+        let props = &node["window_properties"];
+        self.matches(
+            props["class"].as_str(),
+            props["instance"].as_str(),
+            props["title"].as_str(),
+        )
+    }
+}
+
+impl From<Option<Table>> for WindowFilter {
+    fn from(mut value: Option<Table>) -> Self {
+        value.take().map_or_else(Self::default, |table| Self {
+            class: table.get("class").ok(),
+            instance: table.get("instance").ok(),
+            title: table.get("title").ok(),
+        })
+    }
+}
@@ -1,28 +1,53 @@
+mod check;
 mod error;
+mod event_loop;
+mod ewmh;
+mod i3;
+mod inspect;
 mod key_maps;
 mod logger;
 mod lua;
 mod config;
+mod lock;
 pub mod macros;
+mod proc;
+mod settings;
+mod signals;
+mod timer;
 mod x11_kb;
+mod xconn;
+mod xinput;
+mod xkb;
 
-use std::path::PathBuf;
+use std::{os::unix::process::CommandExt, path::PathBuf, process::ExitCode};
 
 use crate::{
     error::{AppError, AppResult},
+    event_loop::EventLoop,
+    inspect::Inspector,
     key_maps::KeyMap,
     logger::log,
+    lock::InstanceLock,
     lua::LuaEngine,
+    signals::{Shutdown, SignalHandler, Signals},
     x11_kb::X11Kb,
 };
 use clap::{Parser, Subcommand};
 
 #[derive(Subcommand)]
-enum Mode {
+pub(crate) enum Mode {
     /// List Keybinds
     List,
+    /// Check the config for problems
+    Check,
+    /// Print the keycode, keysym, modifiers and matching keymaps of every key pressed
+    Inspect,
     /// Run as Daemon
-    Daemon,
+    Daemon {
+        /// Ask an already running daemon to exit and take over its keys
+        #[arg(long)]
+        replace: bool,
+    },
 }
 
 #[derive(Parser)]
@@ -36,25 +61,84 @@ struct AppArgs {
     mode: Mode,
 }
 
-fn run() -> AppResult<()> {
+fn run() -> AppResult<ExitCode> {
     let args = AppArgs::parse();
+
+    // Taken before the config is loaded so a second instance does not run any of its commands
+    let _lock = match args.mode {
+        Mode::Daemon { replace } => Some(InstanceLock::acquire(replace)?),
+        _ => None,
+    };
     let engine = LuaEngine::new(&args)?;
 
     match args.mode {
         Mode::List => engine.keymaps.print_maps(),
-        Mode::Daemon => {
-            let mut kb = X11Kb::new()?;
+        Mode::Check => {
+            if engine.problems.print() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Mode::Inspect => {
+            let signals = Signals::install(&signals::HANDLED)?;
             let keymaps = engine.keymaps.read().map_err(|_| AppError::ReadLockError)?;
-            kb.register(&keymaps);
-            kb.listen();
+            let Some(inspector) = Inspector::new(engine.x.display()?, &keymaps, &engine.settings)
+            else {
+                return Ok(ExitCode::FAILURE);
+            };
+
+            let mut event_loop = EventLoop::default();
+            event_loop.register(SignalHandler::new(signals, engine.procs.clone()));
+            event_loop.register(inspector);
+            if let Shutdown::Exit(code) = event_loop.run() {
+                return Ok(ExitCode::from(code));
+            }
+        }
+        Mode::Daemon { .. } => {
+            let signals = Signals::install(&signals::HANDLED)?;
+            // Children spawned while loading the config might already have exited
+            engine.procs.reap();
+
+            let shutdown = {
+                let mut kb = X11Kb::new(engine.x.display()?, &engine.settings);
+                kb.register(engine.keymaps.clone());
+
+                let mut event_loop = EventLoop::default();
+                event_loop.register(SignalHandler::new(signals, engine.procs.clone()));
+                event_loop.register(engine.timers.clone());
+                event_loop.register(engine.i3_events());
+                event_loop.register(kb);
+                // Dropping the loop at the end of this block releases all grabs
+                event_loop.run()
+            };
+
+            match shutdown {
+                Shutdown::Exit(code) => {
+                    engine.shutdown("exit");
+                    return Ok(ExitCode::from(code));
+                }
+                Shutdown::Restart => {
+                    engine.shutdown("restart");
+                    drop(engine);
+                    log("Restarting crusty-keys");
+
+                    // The lock file is opened with O_CLOEXEC so the new image can take it again
+                    let err = std::process::Command::new(std::env::current_exe()?)
+                        .args(std::env::args_os().skip(1))
+                        .exec();
+                    return Err(err.into());
+                }
+            }
         }
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-fn main() -> AppResult<()> {
-    if let Err(e) = run() {
-        log(e.to_string().as_str());
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            log(e.to_string().as_str());
+            ExitCode::FAILURE
+        }
     }
-    Ok(())
 }
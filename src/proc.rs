@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, RwLock},
+};
+
+use libc::c_int;
+use mlua::{UserData, UserDataFields, UserDataMethods, Value};
+
+/// Handle to a child process spawned through `ck.util.run` or `ck.proc.toggle`.
+#[derive(Clone)]
+pub struct Process {
+    pub pid: u32,
+    pub name: Option<String>,
+    status: Arc<RwLock<Option<i32>>>,
+}
+
+impl Process {
+    fn new(pid: u32, name: Option<String>) -> Self {
+        Self {
+            pid,
+            name,
+            status: Arc::default(),
+        }
+    }
+
+    fn set_status(&self, status: c_int) {
+        if let Ok(mut s) = self.status.write() {
+            *s = Some(exit_code(status));
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.status.read().ok().and_then(|s| *s)
+    }
+
+    pub fn is_running(&self) -> bool {
+        if self.exit_code().is_some() {
+            return false;
+        }
+
+        // The reaper might not have run yet, so we check on the child ourself.
+        let mut status = 0;
+        match unsafe { libc::waitpid(self.pid as i32, &mut status, libc::WNOHANG) } {
+            0 => true,
+            pid if pid == self.pid as i32 => {
+                self.set_status(status);
+                false
+            }
+            // ECHILD, the reaper already collected it
+            _ => false,
+        }
+    }
+
+    pub fn kill(&self, signal: c_int) -> bool {
+        if !self.is_running() {
+            return false;
+        }
+
+        // Children are spawned in their own process group, so we signal the whole group to also
+        // catch anything the command forked. If the child left the group we fall back to the pid.
+        unsafe {
+            libc::kill(-(self.pid as i32), signal) == 0 || libc::kill(self.pid as i32, signal) == 0
+        }
+    }
+
+    pub fn wait(&self) -> Option<i32> {
+        loop {
+            if let Some(code) = self.exit_code() {
+                return Some(code);
+            }
+
+            let mut status = 0;
+            match unsafe { libc::waitpid(self.pid as i32, &mut status, 0) } {
+                pid if pid == self.pid as i32 => {
+                    self.set_status(status);
+                }
+                _ => match io::Error::last_os_error().raw_os_error() {
+                    Some(libc::EINTR) => {}
+                    _ => return None,
+                },
+            }
+        }
+    }
+}
+
+impl UserData for Process {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("pid", |_, this| Ok(this.pid));
+        fields.add_field_method_get("name", |_, this| Ok(this.name.clone()));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("is_running", |_, this, ()| Ok(this.is_running()));
+        methods.add_method("kill", |_, this, signal: Option<Value>| {
+            let signal = match signal {
+                None | Some(Value::Nil) => libc::SIGTERM,
+                Some(Value::Integer(i)) => i as c_int,
+                Some(Value::String(s)) => signal_from_name(&s.to_str()?).ok_or_else(|| {
+                    mlua::Error::runtime(format!("Unknown signal: {}", s.display()))
+                })?,
+                Some(v) => {
+                    return Err(mlua::Error::runtime(format!(
+                        "Expected signal name or number, got {}",
+                        v.type_name()
+                    )));
+                }
+            };
+            Ok(this.kill(signal))
+        });
+        methods.add_method("wait", |_, this, ()| Ok(this.wait()));
+    }
+}
+
+/// All children we spawned that have not been reaped yet.
+#[derive(Default, Clone)]
+pub struct Processes(pub Arc<RwLock<HashMap<u32, Process>>>);
+crate::deref!(Processes => Arc<RwLock<HashMap<u32, Process>>>);
+
+impl Processes {
+    pub fn track(&self, pid: u32, name: Option<String>) -> Process {
+        let process = Process::new(pid, name);
+        if let Ok(mut procs) = self.write() {
+            procs.insert(pid, process.clone());
+        }
+        process
+    }
+
+    /// Returns the first running process that was started under `name`.
+    pub fn find(&self, name: &str) -> Option<Process> {
+        self.read().ok().and_then(|procs| {
+            procs
+                .values()
+                .find(|p| p.name.as_deref() == Some(name) && p.is_running())
+                .cloned()
+        })
+    }
+
+    /// Collect every child that exited, so they don't linger around as zombies.
+    pub fn reap(&self) {
+        loop {
+            let mut status = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+            if pid <= 0 {
+                break;
+            }
+
+            if let Ok(mut procs) = self.write()
+                && let Some(process) = procs.remove(&(pid as u32))
+            {
+                process.set_status(status);
+            }
+        }
+
+        // Children collected through `wait` or `is_running` are already done
+        if let Ok(mut procs) = self.write() {
+            procs.retain(|_, p| p.exit_code().is_none());
+        }
+    }
+}
+
+fn exit_code(status: c_int) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+pub fn signal_from_name(name: &str) -> Option<c_int> {
+    let name = name.to_uppercase();
+    match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        "TERM" => Some(libc::SIGTERM),
+        "CONT" => Some(libc::SIGCONT),
+        "STOP" => Some(libc::SIGSTOP),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_names_are_case_insensitive_with_optional_prefix() {
+        assert_eq!(signal_from_name("TERM"), Some(libc::SIGTERM));
+        assert_eq!(signal_from_name("sigkill"), Some(libc::SIGKILL));
+        assert_eq!(signal_from_name("SigUsr1"), Some(libc::SIGUSR1));
+        assert_eq!(signal_from_name("hup"), Some(libc::SIGHUP));
+    }
+
+    #[test]
+    fn unknown_signal_names_are_rejected() {
+        assert_eq!(signal_from_name("SIG"), None);
+        assert_eq!(signal_from_name("SIGSIGTERM"), None);
+        assert_eq!(signal_from_name("9"), None);
+        assert_eq!(signal_from_name(""), None);
+    }
+}
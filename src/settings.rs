@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+use mlua::Table;
+
+/// Options set through the `ck.settings` table, they are read once the config is loaded.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Window manager speaking the i3 IPC protocol, "i3" or "sway".
+    pub ipc: Option<String>,
+    /// Socket path overriding the one the IPC backend would use.
+    pub ipc_socket: Option<PathBuf>,
+    /// Lock modifiers keymaps work with regardless of their state, e.g. "capslock" or "mod5".
+    /// Found in the modifier mapping when not set.
+    pub ignore_modifiers: Option<Vec<String>>,
+}
+
+impl From<Table> for Settings {
+    fn from(value: Table) -> Self {
+        Settings {
+            ipc: value.get("ipc").ok(),
+            ipc_socket: value.get::<String>("ipc_socket").ok().map(PathBuf::from),
+            ignore_modifiers: value.get("ignore_modifiers").ok(),
+        }
+    }
+}
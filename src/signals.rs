@@ -0,0 +1,138 @@
+use std::{
+    io,
+    os::fd::RawFd,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use libc::c_int;
+
+use crate::{event_loop::EventSource, proc::Processes};
+
+// Write end of the self-pipe, the signal handler can only touch async-signal-safe state so the
+// fd is kept in a static.
+static PIPE_WR: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(sig: c_int) {
+    unsafe {
+        // write(2) may clobber errno, which would confuse the code we interrupted
+        let errno = *libc::__errno_location();
+        let byte = sig as u8;
+        libc::write(
+            PIPE_WR.load(Ordering::Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Signals the daemon reacts to.
+pub const HANDLED: [c_int; 5] = [
+    libc::SIGTERM,
+    libc::SIGINT,
+    libc::SIGHUP,
+    libc::SIGUSR1,
+    libc::SIGCHLD,
+];
+
+/// Why the daemon stops listening for events.
+pub enum Shutdown {
+    /// Exit with the given status code.
+    Exit(u8),
+    /// Restart the daemon, so the config is loaded again.
+    Restart,
+}
+
+impl Shutdown {
+    pub fn from_signal(sig: c_int) -> Option<Self> {
+        match sig {
+            // Being asked to stop is a clean exit
+            libc::SIGTERM | libc::SIGINT | libc::SIGHUP => Some(Shutdown::Exit(0)),
+            libc::SIGUSR1 => Some(Shutdown::Restart),
+            _ => None,
+        }
+    }
+}
+
+/// Self-pipe that turns asynchronous signals into bytes that can be read from a normal fd.
+pub struct Signals {
+    read: RawFd,
+}
+
+impl Signals {
+    pub fn install(signals: &[c_int]) -> io::Result<Self> {
+        unsafe {
+            let mut fds = [0; 2];
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            PIPE_WR.store(fds[1], Ordering::Relaxed);
+
+            for sig in signals {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                if libc::sigaction(*sig, &action, std::ptr::null_mut()) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(Self { read: fds[0] })
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.read
+    }
+
+    /// Returns all signals that were delivered since the last call.
+    pub fn drain(&self) -> Vec<c_int> {
+        let mut buf = [0u8; 64];
+        let mut signals = Vec::new();
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.read,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n <= 0 {
+                break;
+            }
+            signals.extend(buf[..n as usize].iter().map(|b| *b as c_int));
+        }
+        signals
+    }
+}
+
+/// Event source that reaps exited children and turns termination signals into a shutdown.
+pub struct SignalHandler {
+    signals: Signals,
+    procs: Processes,
+}
+
+impl SignalHandler {
+    pub fn new(signals: Signals, procs: Processes) -> Self {
+        Self { signals, procs }
+    }
+}
+
+impl EventSource for SignalHandler {
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.signals.fd())
+    }
+
+    fn dispatch(&mut self) -> Option<Shutdown> {
+        for sig in self.signals.drain() {
+            if sig == libc::SIGCHLD {
+                self.procs.reap();
+            } else if let Some(shutdown) = Shutdown::from_signal(sig) {
+                return Some(shutdown);
+            }
+        }
+        None
+    }
+}
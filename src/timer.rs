@@ -0,0 +1,132 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, UserData, UserDataMethods};
+
+use crate::{event_loop::EventSource, logger::log, signals::Shutdown};
+
+pub struct Timer {
+    id: u64,
+    deadline: Instant,
+    interval: Option<Duration>,
+    cb: Function,
+}
+
+#[derive(Default)]
+pub struct TimerQueue {
+    next_id: u64,
+    timers: Vec<Timer>,
+}
+
+/// Callbacks scheduled from Lua, they are run by the event loop of the daemon.
+#[derive(Default, Clone)]
+pub struct Timers(pub Arc<RwLock<TimerQueue>>);
+crate::deref!(Timers => Arc<RwLock<TimerQueue>>);
+
+impl Timers {
+    /// Schedules `cb` to run after `delay`, and every `interval` after that if one is given.
+    pub fn schedule(&self, delay: Duration, interval: Option<Duration>, cb: Function) -> TimerHandle {
+        let mut id = 0;
+        if let Ok(mut queue) = self.write() {
+            queue.next_id += 1;
+            id = queue.next_id;
+            queue.timers.push(Timer {
+                id,
+                deadline: Instant::now() + delay,
+                interval,
+                cb,
+            });
+        }
+        TimerHandle {
+            id,
+            timers: self.clone(),
+        }
+    }
+
+    pub fn cancel(&self, id: u64) -> bool {
+        self.write().is_ok_and(|mut queue| {
+            let len = queue.timers.len();
+            queue.timers.retain(|t| t.id != id);
+            len != queue.timers.len()
+        })
+    }
+
+    pub fn is_active(&self, id: u64) -> bool {
+        self.read()
+            .is_ok_and(|queue| queue.timers.iter().any(|t| t.id == id))
+    }
+
+    /// Takes the callback of timer `id` out of the queue, interval timers are rescheduled.
+    fn take_expired(&self, id: u64, now: Instant) -> Option<Function> {
+        let mut queue = self.write().ok()?;
+        let index = queue.timers.iter().position(|t| t.id == id)?;
+
+        match queue.timers[index].interval {
+            Some(interval) => {
+                let timer = &mut queue.timers[index];
+                // Don't try to catch up on missed ticks, e.g. after a suspend
+                timer.deadline = (timer.deadline + interval).max(now);
+                Some(timer.cb.clone())
+            }
+            None => Some(queue.timers.remove(index).cb),
+        }
+    }
+}
+
+impl EventSource for Timers {
+    fn deadline(&self) -> Option<Instant> {
+        self.read()
+            .ok()?
+            .timers
+            .iter()
+            .map(|t| t.deadline)
+            .min()
+    }
+
+    fn dispatch(&mut self) -> Option<Shutdown> {
+        let now = Instant::now();
+        let mut expired = self
+            .read()
+            .ok()?
+            .timers
+            .iter()
+            .filter(|t| t.deadline <= now)
+            .map(|t| (t.deadline, t.id))
+            .collect::<Vec<_>>();
+        expired.sort();
+
+        // The lock is not held while calling into Lua, callbacks are free to schedule or cancel
+        // timers. A timer cancelled by an earlier callback of this batch is skipped.
+        for (_, id) in expired {
+            if let Some(cb) = self.take_expired(id, now)
+                && let Err(e) = cb.call::<()>(())
+            {
+                log(format!("Timer callback failed: {e}"));
+            }
+        }
+        None
+    }
+}
+
+/// Handle returned to Lua so a timer can be cancelled.
+pub struct TimerHandle {
+    id: u64,
+    timers: Timers,
+}
+
+impl TimerHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl UserData for TimerHandle {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("cancel", |_, this, ()| Ok(this.timers.cancel(this.id)));
+        methods.add_method("is_active", |_, this, ()| {
+            Ok(this.timers.is_active(this.id))
+        });
+    }
+}
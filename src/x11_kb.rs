@@ -1,12 +1,37 @@
 #![allow(non_upper_case_globals)]
-use std::{collections::HashMap, ffi::c_ulong};
+use std::{
+    collections::HashMap,
+    ffi::{c_int, c_ulong},
+    os::fd::RawFd,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use x11_dl::xlib::{self, BadAccess, BadValue, BadWindow, GrabModeAsync, True};
+use x11_dl::{
+    keysym,
+    xlib::{
+        self, BadAccess, BadValue, BadWindow, GrabModeAsync, LockMask, Mod1Mask, Mod2Mask,
+        Mod3Mask, Mod4Mask, Mod5Mask, ShiftMask, True,
+    },
+};
 
-use crate::{key_maps::KbCodeType, logger::log, KeyMap};
+use crate::{
+    KeyMap,
+    event_loop::EventSource,
+    key_maps::{KbCodeType, KeyMaps, Layout, MapError, Modifier, kbcode::KeyKind},
+    logger::log,
+    settings::Settings,
+    signals::Shutdown,
+    xconn::Display,
+    xinput::{RawEvent, XInput},
+    xkb,
+};
 
 type ListenerID = (i32, u32);
 
+/// Modifier bits of a key event state, the higher bits hold the XKB group and mouse buttons.
+const MODIFIER_BITS: u32 = 0xff;
+
 fn grab_key_code_to_msg(code: u8) -> Result<(), &'static str> {
     match code {
         BadAccess => Err(
@@ -20,67 +45,219 @@ fn grab_key_code_to_msg(code: u8) -> Result<(), &'static str> {
     }
 }
 
-pub struct X11Kb<'a> {
-    display: *mut xlib::Display,
-    root: c_ulong,
-    xlib: xlib::Xlib,
-    handlers: HashMap<ListenerID, &'a mlua::Function>,
+/// A key with timed callbacks that is held down.
+struct Press {
+    keymap: Rc<KeyMap>,
+    /// When the hold callback runs, None once it ran or if there is none.
+    hold_at: Option<Instant>,
+    held: bool,
+    /// Pressed again while the first tap was waiting for a double tap.
+    second: bool,
+}
+
+/// A tap waiting to see whether a second tap follows.
+struct PendingTap {
+    keymap: Rc<KeyMap>,
+    until: Instant,
+}
+
+/// The keys with timed callbacks that are held down or waiting for a double tap.
+#[derive(Default)]
+struct Presses {
+    /// By keycode.
+    pressed: HashMap<u32, Press>,
+    pending_tap: Option<PendingTap>,
+}
+
+impl Presses {
+    fn press(&mut self, keycode: u32, keymap: Rc<KeyMap>) {
+        // Autorepeat, detectable autorepeat leaves out the releases in between
+        if self.pressed.contains_key(&keycode) {
+            return;
+        }
+        // Another key ends the wait for a double tap
+        if let Some(pending) = self
+            .pending_tap
+            .take_if(|pending| !Rc::ptr_eq(&pending.keymap, &keymap))
+        {
+            run_tap(&pending.keymap);
+        }
+
+        let timing = &keymap.options.timing;
+        if timing.is_empty() {
+            let _ = keymap.cb.call::<()>(());
+            return;
+        }
+        let hold_at = timing
+            .hold
+            .as_ref()
+            .map(|_| Instant::now() + Duration::from_millis(timing.hold_ms));
+        // A tap of the same keymap is still waiting, this press decides whether it's a double tap
+        let second = self.pending_tap.take().is_some();
+        self.pressed.insert(
+            keycode,
+            Press {
+                keymap,
+                hold_at,
+                held: false,
+                second,
+            },
+        );
+    }
+
+    fn release(&mut self, keycode: u32) {
+        let Some(press) = self.pressed.remove(&keycode) else {
+            return;
+        };
+        if press.held {
+            return;
+        }
+
+        let timing = &press.keymap.options.timing;
+        match (&timing.double_tap, press.second) {
+            (Some(double_tap), true) => {
+                let _ = double_tap.call::<()>(());
+            }
+            (Some(_), false) => {
+                let pending = PendingTap {
+                    keymap: press.keymap.clone(),
+                    until: Instant::now() + Duration::from_millis(timing.double_tap_ms),
+                };
+                // Another key released while its own tap was waiting
+                if let Some(other) = self.pending_tap.replace(pending) {
+                    run_tap(&other.keymap);
+                }
+            }
+            (None, _) => run_tap(&press.keymap),
+        }
+    }
+
+    /// Runs the hold and tap callbacks whose time has come.
+    fn timeout(&mut self) {
+        let now = Instant::now();
+        for press in self.pressed.values_mut() {
+            if press.hold_at.take_if(|hold_at| *hold_at <= now).is_none() {
+                continue;
+            }
+            press.held = true;
+            // Holding the second press makes the first one a tap of its own
+            if press.second {
+                run_tap(&press.keymap);
+            }
+            if let Some(ref hold) = press.keymap.options.timing.hold {
+                let _ = hold.call::<()>(());
+            }
+        }
+        if let Some(pending) = self.pending_tap.take_if(|pending| pending.until <= now) {
+            run_tap(&pending.keymap);
+        }
+    }
+
+    /// When the next hold or tap callback is due.
+    fn deadline(&self) -> Option<Instant> {
+        let hold_at = self.pressed.values().filter_map(|press| press.hold_at);
+        let tap_until = self.pending_tap.as_ref().map(|pending| pending.until);
+        hold_at.chain(tap_until).min()
+    }
+
+    /// Forgets the held keys and the waiting tap without running anything, their releases may
+    /// never come or come from another key.
+    fn clear(&mut self) {
+        self.pressed.clear();
+        self.pending_tap = None;
+    }
 }
 
-impl<'a> X11Kb<'a> {
-    pub fn new() -> crate::error::AppResult<Self> {
-        let xlib = xlib::Xlib::open()?;
+pub struct X11Kb {
+    x: Rc<Display>,
+    /// The keymaps set in Lua, callbacks may still set keymaps while the daemon runs.
+    shared: KeyMaps,
+    /// The keymaps the keys are grabbed for.
+    keymaps: Vec<Rc<KeyMap>>,
+    /// Keymaps by grabbed key, keymaps only differing in the side of a modifier share a grab.
+    handlers: HashMap<ListenerID, Vec<Rc<KeyMap>>>,
+    /// The modifier mask set by each modifier key.
+    masks: HashMap<c_ulong, u32>,
+    /// `ck.settings.ignore_modifiers`, None to find the lock modifiers in the modifier mapping.
+    ignore_modifiers: Option<Vec<String>>,
+    /// Mask of the lock modifiers that are grabbed in every combination.
+    ignored: u32,
+    /// First event code of the XKB extension, None when the server lacks it.
+    xkb_event: Option<c_int>,
+    /// The active XKB group (layout).
+    group: u8,
+    /// Raw key events, only selected when a modifier is bound on its own.
+    xinput: Option<XInput>,
+    /// Tap keymaps by the keycode of the modifier key.
+    taps: HashMap<u8, Rc<KeyMap>>,
+    /// The modifier key that is held without any other key being pressed yet, and since when.
+    tapping: Option<(u8, Instant)>,
+    presses: Presses,
+    /// Set when the keyboard mapping or the keymaps changed, the keys are grabbed again once all
+    /// queued events are handled.
+    stale: bool,
+}
 
+impl X11Kb {
+    pub fn new(x: Rc<Display>, settings: &Settings) -> Self {
         unsafe {
-            let display = (xlib.XOpenDisplay)(std::ptr::null());
-            let root = (xlib.XDefaultRootWindow)(display);
             let mut supported_rtrn = std::mem::zeroed();
-            (xlib.XkbSetDetectableAutoRepeat)(display, 1, &mut supported_rtrn);
+            (x.xlib.XkbSetDetectableAutoRepeat)(x.ptr, 1, &mut supported_rtrn);
+            // Tells when another client grabs the keyboard
+            (x.xlib.XSelectInput)(x.ptr, x.root, xlib::FocusChangeMask);
+        }
 
-            Ok(Self {
-                display,
-                root,
-                xlib,
-                handlers: HashMap::new(),
-            })
+        let xkb_event = xkb::event_base(&x);
+        if xkb_event.is_some() {
+            xkb::select_events(&x);
+        }
+        let group = xkb::current_group(&x);
+        let masks = xkb::modifier_masks(&x);
+
+        Self {
+            x,
+            shared: KeyMaps::default(),
+            keymaps: Vec::new(),
+            handlers: HashMap::new(),
+            masks,
+            ignore_modifiers: settings.ignore_modifiers.clone(),
+            ignored: 0,
+            xinput: None,
+            taps: HashMap::new(),
+            tapping: None,
+            presses: Presses::default(),
+            xkb_event,
+            group,
+            stale: false,
         }
     }
 
-    fn grab_key(&self, keymap: &'a KeyMap) -> Vec<(i32, u32)> {
+    fn grab_key(&self, keymap: &KeyMap, keycode: i32, shift: u32) -> Vec<(i32, u32)> {
         unsafe {
-            let keycode = match keymap.map.code {
-                KbCodeType::Sym(ref kb_sym) => (self.xlib.XKeysymToKeycode)(self.display, kb_sym.to_code() as u64) as i32,
-                KbCodeType::Code(ref kb_code) => kb_code.to_code() as i32,
-            };
-
-
-            // Because Numlock & Capslock are modifiers as well we need to add the keymaps with
-            // these as well. Else the keymap will not work if capslock and or numlock is on.
-            keymap
-                .map
-                .modifiers
-                .as_universal()
+            // Because lock keys like Numlock & Capslock are modifiers as well we need to add the
+            // keymaps with these as well. Else the keymap will not work if one of them is on.
+            Modifier::as_universal(keymap.map.modifiers.mask(&self.masks) | shift, self.ignored)
                 .into_iter()
                 .filter_map(|modifier| {
                     // We first have to unregister our key grab before we can register it again
                     // this so that if any othere window has a grab on the keymap it is first
                     // undone, this is needed because when we register a keygrab when its still
                     // grabbed be a different window the grab will fail
-                    if let Err(e) = grab_key_code_to_msg((self.xlib.XUngrabKey)(
-                        self.display,
+                    if let Err(e) = grab_key_code_to_msg((self.x.xlib.XUngrabKey)(
+                        self.x.ptr,
                         keycode,
                         modifier,
-                        self.root,
+                        self.x.root,
                     ) as u8)
                     {
                         log(format!("Ungrap key error: {e}"));
                     }
 
-                    match grab_key_code_to_msg((self.xlib.XGrabKey)(
-                        self.display,
+                    match grab_key_code_to_msg((self.x.xlib.XGrabKey)(
+                        self.x.ptr,
                         keycode,
                         modifier,
-                        self.root,
+                        self.x.root,
                         True,
                         GrabModeAsync,
                         GrabModeAsync,
@@ -97,28 +274,453 @@ impl<'a> X11Kb<'a> {
         }
     }
 
-    pub fn register(&mut self, keymaps: &'a [KeyMap]) {
-        keymaps.iter().for_each(|map| {
-            self.grab_key(map).into_iter().for_each(|id| {
-                self.handlers.insert(id, &map.cb);
+    pub fn register(&mut self, keymaps: KeyMaps) {
+        self.shared = keymaps;
+        self.update_keymaps();
+        self.ignored = ignored_mask(&self.masks, self.ignore_modifiers.as_deref());
+        self.open_xinput();
+        self.grab_all();
+    }
+
+    /// Selects raw key events once a modifier is bound on its own.
+    fn open_xinput(&mut self) {
+        if self.xinput.is_some() || !self.keymaps.iter().any(|map| map.options.tap) {
+            return;
+        }
+        self.xinput = XInput::open(&self.x);
+        if let Some(ref xinput) = self.xinput {
+            xinput.select_raw_events(&self.x);
+        }
+    }
+
+    /// Takes over the keymaps set since the keys were grabbed, returns whether there were any.
+    /// Nothing changes while a callback is setting a keymap, it is seen on the next call.
+    fn update_keymaps(&mut self) -> bool {
+        let Ok(keymaps) = self.shared.try_read() else {
+            return false;
+        };
+        if keymaps.len() == self.keymaps.len()
+            && keymaps.iter().zip(&self.keymaps).all(|(a, b)| Rc::ptr_eq(a, b))
+        {
+            return false;
+        }
+        self.keymaps = keymaps.clone();
+        true
+    }
+
+    /// Marks the keys to be grabbed again when callbacks set keymaps, e.g. from `ck.i3.on` or
+    /// a timer.
+    fn check_keymaps(&mut self) {
+        if self.update_keymaps() {
+            if !self.stale {
+                log("Keymaps changed, regrabbing keys");
+            }
+            self.open_xinput();
+            self.stale = true;
+        }
+    }
+
+    /// Maps the keycodes of every modifier bound on its own to its keymap. Keymaps for one side,
+    /// like `<lsuper>`, take precedence over keymaps for both sides.
+    fn find_taps(&mut self) {
+        self.taps.clear();
+        let mut taps = self
+            .keymaps
+            .iter()
+            .filter_map(|map| match map.map.code {
+                KbCodeType::Tap(modifier) => Some((map.clone(), modifier)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        taps.sort_by_key(|(_, modifier)| modifier.side_keysym().is_some());
+
+        for (map, modifier) in taps {
+            modifier.tap_keysyms(&self.masks).into_iter().for_each(|sym| {
+                xkb::keycodes(&self.x, c_ulong::from(sym), &[0])
+                    .into_iter()
+                    .for_each(|(keycode, _)| {
+                        self.taps.insert(keycode, map.clone());
+                    });
+            });
+        }
+    }
+
+    fn on_raw_event(&mut self, event: RawEvent) {
+        match event {
+            RawEvent::KeyPress(keycode) if self.taps.contains_key(&keycode) => {
+                self.tapping = Some((keycode, Instant::now()))
+            }
+            RawEvent::KeyRelease(keycode)
+                if self.tapping.is_some_and(|(tapping, _)| tapping == keycode) =>
+            {
+                // Holding the modifier longer than hold_ms isn't a tap, e.g. Super held while
+                // looking for a window to click
+                if let Some((_, since)) = self.tapping.take()
+                    && let Some(map) = self.taps.get(&keycode)
+                    && since.elapsed() < Duration::from_millis(map.options.timing.hold_ms)
+                {
+                    let _ = map.cb.call::<()>(());
+                }
+            }
+            // Any other key or a click in between makes it a regular modifier press
+            RawEvent::KeyPress(_) | RawEvent::ButtonPress => self.tapping = None,
+            RawEvent::KeyRelease(_) => {}
+        }
+    }
+
+    fn grab_all(&mut self) {
+        self.find_taps();
+        self.keymaps.clone().into_iter().for_each(|map| {
+            keycodes(&self.x, &map, self.group).into_iter().for_each(|(keycode, shift)| {
+                self.grab_key(&map, keycode, shift).into_iter().for_each(|id| {
+                    self.handlers.entry(id).or_default().push(map.clone());
+                });
             });
         });
     }
 
-    pub fn listen(&self) {
+    fn ungrab_all(&mut self) {
+        self.handlers.drain().for_each(|((keycode, modifier), _)| unsafe {
+            (self.x.xlib.XUngrabKey)(self.x.ptr, keycode, modifier, self.x.root);
+        });
+    }
+
+    /// Grabs all keys again, the keys producing a symbol may have moved.
+    fn regrab(&mut self) {
+        self.stale = false;
+        self.presses.clear();
+        self.masks = xkb::modifier_masks(&self.x);
+        self.ignored = ignored_mask(&self.masks, self.ignore_modifiers.as_deref());
+        self.ungrab_all();
+        self.grab_all();
+    }
+
+    fn on_group_change(&mut self, group: u8) {
+        if group == self.group {
+            return;
+        }
+        self.group = group;
+        // The held keys may produce other symbols now
+        self.presses.clear();
+
+        if self
+            .keymaps
+            .iter()
+            .any(|map| map.options.layout == Layout::Current)
+        {
+            log(format!("Layout switched to group {}, regrabbing keys", group + 1));
+            self.stale = true;
+        }
+    }
+
+    fn on_mapping_change(&mut self, event: &mut xlib::XEvent) {
+        let request = unsafe { event.mapping.request };
+        // Pointer button mappings don't affect any keys
+        if request == xlib::MappingPointer {
+            return;
+        }
+
+        unsafe {
+            (self.x.xlib.XRefreshKeyboardMapping)(&mut event.mapping);
+        }
+        if !self.stale {
+            log("Keyboard mapping changed, regrabbing keys");
+        }
+        self.stale = true;
+    }
+
+    fn on_new_keyboard(&mut self) {
+        if !self.stale {
+            log("Keyboard changed, regrabbing keys");
+        }
+        // The new keyboard may start out in another group
+        self.group = xkb::current_group(&self.x);
+        self.stale = true;
+    }
+
+    /// The keymap to run out of the keymaps sharing a grab. Keymaps with side specific modifiers
+    /// like `<lsuper>` only match while that key is held, the most specific match wins.
+    fn pick(&self, keymaps: &[Rc<KeyMap>]) -> Option<Rc<KeyMap>> {
+        keymaps
+            .iter()
+            .map(|keymap| (keymap, keymap.map.modifiers.sides()))
+            .filter(|(_, sides)| {
+                sides
+                    .iter()
+                    .all(|sym| xkb::is_pressed(&self.x, c_ulong::from(*sym)))
+            })
+            .max_by_key(|(_, sides)| sides.len())
+            .map(|(keymap, _)| keymap.clone())
+    }
+
+    fn dispatch_event(&mut self) {
         unsafe {
-            loop {
-                let mut event: xlib::XEvent = std::mem::zeroed();
-                (self.xlib.XNextEvent)(self.display, &mut event);
-
-                if let (true, Some(cb)) = (
-                    event.get_type() == xlib::KeyPress,
-                    self.handlers
-                        .get(&(event.key.keycode as i32, event.key.state)),
-                ) {
-                    let _ = cb.call::<()>(());
+            let mut event: xlib::XEvent = std::mem::zeroed();
+            (self.x.xlib.XNextEvent)(self.x.ptr, &mut event);
+
+            if let Some(raw) = self
+                .xinput
+                .as_ref()
+                .and_then(|xinput| xinput.raw_event(&self.x, &mut event))
+            {
+                self.on_raw_event(raw);
+            }
+            if event.get_type() == xlib::MappingNotify {
+                self.on_mapping_change(&mut event);
+            }
+            // Another client grabbed the keyboard, it gets the releases of the held keys
+            if event.get_type() == xlib::FocusOut && event.focus_change.mode == xlib::NotifyGrab {
+                self.presses.clear();
+            }
+            if let Some(base) = self.xkb_event {
+                if let Some(group) = xkb::group_change(&event, base) {
+                    self.on_group_change(group);
+                }
+                if xkb::is_new_keyboard(&event, base) {
+                    self.on_new_keyboard();
                 }
             }
+
+            if let (true, Some(keymaps)) = (
+                event.get_type() == xlib::KeyPress,
+                self.handlers
+                    .get(&(event.key.keycode as i32, event.key.state & MODIFIER_BITS)),
+            ) && let Some(keymap) = self.pick(keymaps)
+            {
+                self.presses.press(event.key.keycode, keymap);
+            }
+            if event.get_type() == xlib::KeyRelease {
+                self.presses.release(event.key.keycode);
+            }
+        }
+    }
+}
+
+impl EventSource for X11Kb {
+    fn fd(&self) -> Option<RawFd> {
+        Some(unsafe { (self.x.xlib.XConnectionNumber)(self.x.ptr) })
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.presses.deadline()
+    }
+
+    fn pending(&mut self) -> bool {
+        // Callbacks of the other sources may have set keymaps
+        self.check_keymaps();
+        // Xlib may already have read events into its queue, those won't wake up poll.
+        self.stale || unsafe { (self.x.xlib.XPending)(self.x.ptr) > 0 }
+    }
+
+    fn dispatch(&mut self) -> Option<Shutdown> {
+        while unsafe { (self.x.xlib.XPending)(self.x.ptr) > 0 } {
+            self.dispatch_event();
+        }
+        self.presses.timeout();
+        self.check_keymaps();
+        // A layout change often comes as a burst of events, only grab once they are all handled
+        if self.stale {
+            self.regrab();
+        }
+        None
+    }
+}
+
+impl Drop for X11Kb {
+    fn drop(&mut self) {
+        self.ungrab_all();
+        unsafe {
+            (self.x.xlib.XFlush)(self.x.ptr);
+        }
+    }
+}
+
+/// Keycodes `keymap` has to be grabbed on with the current keyboard mapping, together with
+/// the modifiers needed to reach the symbol on that key.
+pub fn keycodes(x: &Display, keymap: &KeyMap, group: u8) -> Vec<(i32, u32)> {
+    let kb_sym = match keymap.map.code {
+        KbCodeType::Sym(ref kb_sym) => kb_sym,
+        KbCodeType::Code(ref kb_code) => return vec![(kb_code.to_code() as i32, 0)],
+        KbCodeType::Tap(_) => return vec![],
+    };
+    let groups = match keymap.options.layout {
+        Layout::Current => &[group][..],
+        Layout::Any => &xkb::GROUPS[..],
+        Layout::Physical => &[0][..],
+    };
+
+    let keycodes = xkb::keycodes(x, kb_sym.to_code() as u64, groups);
+    if keycodes.is_empty() {
+        log(format!("No key produces \"{}\" in the current layout", keymap.s));
+    }
+
+    // Keys having the symbol on their plain level are preferred, symbols that are only found
+    // on the shifted level, like "!", are grabbed with Shift.
+    let plain = keycodes.iter().any(|(_, level)| *level == 0);
+    let shifted = keycodes.iter().any(|(_, level)| *level != 0);
+    if !plain && shifted && keymap.map.modifiers.has_shift() {
+        // Shift can't be pressed twice, "<shift>+!" would be the same keys as "!"
+        let key = keymap
+            .s
+            .split('+')
+            .map(str::trim)
+            .find(|s| !matches!(KeyKind::from(*s), KeyKind::Mod(_)))
+            .unwrap_or_default();
+        log(format!(
+            "{}: invalid keymap \"{}\": {}",
+            keymap.location,
+            keymap.s,
+            MapError::ShiftedSymbol(key.to_string())
+        ));
+        return vec![];
+    }
+    if plain && shifted {
+        log(format!(
+            "{}: \"{}\" is also on the shifted level of some keys, only the keys having it on their plain level are bound",
+            keymap.location, keymap.s
+        ));
+    }
+    keycodes
+        .into_iter()
+        .filter(|(_, level)| !plain || *level == 0)
+        .map(|(keycode, level)| {
+            let shift = if level == 0 { 0 } else { ShiftMask };
+            (i32::from(keycode), shift)
+        })
+        .collect()
+}
+
+/// The mask of the lock modifiers keymaps ignore, by default Caps Lock and the modifiers Num Lock
+/// and Scroll Lock are mapped to. Scroll Lock is left out when its modifier is shared with
+/// another key.
+pub fn ignored_mask(masks: &HashMap<c_ulong, u32>, names: Option<&[String]>) -> u32 {
+    let lock = |sym: u32| masks.get(&c_ulong::from(sym)).copied();
+    let Some(names) = names else {
+        // Scroll Lock is often put on a modifier that AltGr or another key sets as well,
+        // ignoring it then would ignore that key too
+        let scroll_lock = lock(keysym::XK_Scroll_Lock).filter(|mask| {
+            masks.iter().all(|(sym, other)| {
+                *sym == c_ulong::from(keysym::XK_Scroll_Lock) || *sym == 0 || other != mask
+            })
+        });
+        return LockMask
+            | lock(keysym::XK_Num_Lock).unwrap_or(Mod2Mask)
+            | scroll_lock.unwrap_or_default();
+    };
+
+    names.iter().fold(0, |ignored, name| {
+        let mask = match name.trim_matches(['<', '>']).to_lowercase().as_str() {
+            "capslock" | "lock" => Some(LockMask),
+            "numlock" => lock(keysym::XK_Num_Lock),
+            "scrolllock" => lock(keysym::XK_Scroll_Lock),
+            "mod1" => Some(Mod1Mask),
+            "mod2" => Some(Mod2Mask),
+            "mod3" => Some(Mod3Mask),
+            "mod4" => Some(Mod4Mask),
+            "mod5" => Some(Mod5Mask),
+            _ => {
+                log(format!(
+                    "Unknown modifier \"{name}\" in ck.settings.ignore_modifiers"
+                ));
+                return ignored;
+            }
+        };
+        match mask {
+            Some(mask) => ignored | mask,
+            None => {
+                log(format!(
+                    "No modifier is mapped to {name}, it can't be ignored"
+                ));
+                ignored
+            }
         }
+    })
+}
+
+/// Runs the tap callback of a keymap with timed callbacks, the keymap callback if it has none.
+fn run_tap(keymap: &KeyMap) {
+    let cb = keymap.options.timing.tap.as_ref().unwrap_or(&keymap.cb);
+    let _ = cb.call::<()>(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_maps::{KeyMapOptions, Map};
+
+    fn masks(syms: &[(u32, u32)]) -> HashMap<c_ulong, u32> {
+        syms.iter()
+            .map(|(sym, mask)| (c_ulong::from(*sym), *mask))
+            .collect()
+    }
+
+    #[test]
+    fn ignored_mask_looks_up_the_lock_modifiers() {
+        let masks = masks(&[
+            (keysym::XK_Num_Lock, Mod2Mask),
+            (keysym::XK_Scroll_Lock, Mod3Mask),
+            (keysym::XK_Super_L, Mod4Mask),
+        ]);
+        assert_eq!(ignored_mask(&masks, None), LockMask | Mod2Mask | Mod3Mask);
+    }
+
+    #[test]
+    fn ignored_mask_keeps_a_shared_scroll_lock_modifier() {
+        let masks = masks(&[
+            (keysym::XK_Num_Lock, Mod2Mask),
+            (keysym::XK_Scroll_Lock, Mod5Mask),
+            (keysym::XK_ISO_Level3_Shift, Mod5Mask),
+        ]);
+        assert_eq!(ignored_mask(&masks, None), LockMask | Mod2Mask);
+    }
+
+    /// A keymap on `a` with a tap and a hold callback, each counting its calls in a global.
+    fn timed_keymap(lua: &mlua::Lua) -> mlua::Result<Rc<KeyMap>> {
+        lua.load("taps, holds = 0, 0").exec()?;
+        let options = lua
+            .load(
+                r#"{
+                    tap = function() taps = taps + 1 end,
+                    hold = function() holds = holds + 1 end,
+                    hold_ms = 0,
+                }"#,
+            )
+            .eval::<mlua::Table>()?;
+        Ok(Rc::new(KeyMap {
+            options: KeyMapOptions::from(options),
+            s: "a".to_string(),
+            map: Map::try_from(&"a".to_string()).map_err(mlua::Error::runtime)?,
+            cb: lua.load("function() end").eval()?,
+            location: String::new(),
+        }))
+    }
+
+    #[test]
+    fn cleared_presses_run_nothing_and_are_not_stuck() -> mlua::Result<()> {
+        let lua = mlua::Lua::new();
+        let keymap = timed_keymap(&lua)?;
+        let mut presses = Presses::default();
+
+        presses.press(38, keymap.clone());
+        presses.clear();
+        presses.timeout();
+        presses.release(38);
+        assert_eq!(lua.globals().get::<u32>("taps")?, 0);
+        assert_eq!(lua.globals().get::<u32>("holds")?, 0);
+        assert!(presses.deadline().is_none());
+
+        // Without the clear this press would be taken for autorepeat
+        presses.press(38, keymap);
+        presses.timeout();
+        presses.release(38);
+        assert_eq!(lua.globals().get::<u32>("holds")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn ignored_mask_uses_the_configured_names() {
+        let masks = masks(&[(keysym::XK_Num_Lock, Mod2Mask)]);
+        let names = ["<numlock>", "mod5", "scrolllock"].map(str::to_string);
+        assert_eq!(ignored_mask(&masks, Some(&names)), Mod2Mask | Mod5Mask);
     }
 }
@@ -0,0 +1,376 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    ffi::{CString, c_int, c_long, c_uchar, c_uint, c_ulong},
+    rc::Rc,
+};
+
+use mlua::{IntoLua, Lua};
+use x11_dl::xlib::{self, Atom, Window};
+
+use crate::{
+    error::{AppError, AppResult},
+    logger::log,
+};
+
+/// Connection to the X server shared by the key grabber and the Lua API. It is opened on first
+/// use so configs that never touch X keep working without a display.
+#[derive(Default, Clone)]
+pub struct XConn(Rc<OnceCell<Rc<Display>>>);
+
+impl XConn {
+    pub fn display(&self) -> AppResult<Rc<Display>> {
+        if let Some(display) = self.0.get() {
+            return Ok(display.clone());
+        }
+        let display = Rc::new(Display::open()?);
+        let _ = self.0.set(display.clone());
+        Ok(display)
+    }
+}
+
+pub struct Display {
+    pub xlib: xlib::Xlib,
+    pub ptr: *mut xlib::Display,
+    pub root: Window,
+    atoms: RefCell<HashMap<String, Atom>>,
+}
+
+/// The default handler exits the process, a bad window id passed from Lua should only be logged.
+unsafe extern "C" fn on_x_error(_: *mut xlib::Display, e: *mut xlib::XErrorEvent) -> c_int {
+    let e = unsafe { &*e };
+    log(format!(
+        "X error {} on request {}.{} for resource 0x{:x}",
+        e.error_code, e.request_code, e.minor_code, e.resourceid
+    ));
+    0
+}
+
+impl Display {
+    fn open() -> AppResult<Self> {
+        let xlib = xlib::Xlib::open()?;
+
+        unsafe {
+            let ptr = (xlib.XOpenDisplay)(std::ptr::null());
+            if ptr.is_null() {
+                return Err(AppError::DisplayNotFound);
+            }
+            (xlib.XSetErrorHandler)(Some(on_x_error));
+            let root = (xlib.XDefaultRootWindow)(ptr);
+
+            Ok(Self {
+                xlib,
+                ptr,
+                root,
+                atoms: RefCell::default(),
+            })
+        }
+    }
+
+    pub fn atom(&self, name: &str) -> Atom {
+        if let Some(atom) = self.atoms.borrow().get(name) {
+            return *atom;
+        }
+        let Ok(c_name) = CString::new(name) else {
+            return 0;
+        };
+        let atom = unsafe { (self.xlib.XInternAtom)(self.ptr, c_name.as_ptr(), xlib::False) };
+        self.atoms.borrow_mut().insert(name.to_string(), atom);
+        atom
+    }
+
+    /// Reads `property` of `window`, returns the format and the raw items.
+    fn property(&self, window: Window, property: &str, kind: Atom) -> Option<(c_int, Vec<u8>)> {
+        let mut actual_type = 0;
+        let mut actual_format = 0;
+        let mut nitems: c_ulong = 0;
+        let mut bytes_after = 0;
+        let mut prop: *mut c_uchar = std::ptr::null_mut();
+
+        unsafe {
+            let status = (self.xlib.XGetWindowProperty)(
+                self.ptr,
+                window,
+                self.atom(property),
+                0,
+                c_long::MAX / 4,
+                xlib::False,
+                kind,
+                &mut actual_type,
+                &mut actual_format,
+                &mut nitems,
+                &mut bytes_after,
+                &mut prop,
+            );
+            if status != xlib::Success as i32 || prop.is_null() {
+                return None;
+            }
+
+            // Xlib hands out 32 bit items as longs
+            let size = match actual_format {
+                32 => size_of::<c_long>(),
+                16 => 2,
+                _ => 1,
+            };
+            let bytes = std::slice::from_raw_parts(prop, nitems as usize * size).to_vec();
+            (self.xlib.XFree)(prop as *mut _);
+
+            (actual_type != 0).then_some((actual_format, bytes))
+        }
+    }
+
+    /// Reads a property made of 32 bit items, e.g. CARDINAL, WINDOW or ATOM lists.
+    pub fn get_longs(&self, window: Window, property: &str) -> Vec<c_ulong> {
+        match self.property(window, property, xlib::AnyPropertyType as Atom) {
+            Some((32, bytes)) => bytes
+                .chunks_exact(size_of::<c_ulong>())
+                .filter_map(|c| c.try_into().ok().map(c_ulong::from_ne_bytes))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn get_long(&self, window: Window, property: &str) -> Option<c_ulong> {
+        self.get_longs(window, property).first().copied()
+    }
+
+    /// Reads a text property, lists like WM_CLASS are returned split on the NUL separators.
+    pub fn get_strings(&self, window: Window, property: &str) -> Vec<String> {
+        match self.property(window, property, xlib::AnyPropertyType as Atom) {
+            Some((8, bytes)) => split_strings(&bytes),
+            _ => vec![],
+        }
+    }
+
+    pub fn get_string(&self, window: Window, property: &str) -> Option<String> {
+        self.get_strings(window, property).into_iter().next()
+    }
+
+    /// Replaces `property` of `window` with a UTF8_STRING.
+    pub fn set_string(&self, window: Window, property: &str, value: &str) {
+        unsafe {
+            (self.xlib.XChangeProperty)(
+                self.ptr,
+                window,
+                self.atom(property),
+                self.atom("UTF8_STRING"),
+                8,
+                xlib::PropModeReplace,
+                value.as_ptr(),
+                value.len() as c_int,
+            );
+        }
+        self.flush();
+    }
+
+    /// Replaces `property` of `window` with a list of CARDINALs.
+    pub fn set_longs(&self, window: Window, property: &str, values: &[c_ulong]) {
+        unsafe {
+            (self.xlib.XChangeProperty)(
+                self.ptr,
+                window,
+                self.atom(property),
+                xlib::XA_CARDINAL,
+                32,
+                xlib::PropModeReplace,
+                values.as_ptr() as *const c_uchar,
+                values.len() as c_int,
+            );
+        }
+        self.flush();
+    }
+
+    /// Position relative to the root window and size of `window`.
+    pub fn geometry(&self, window: Window) -> Option<Geometry> {
+        unsafe {
+            let mut attributes: xlib::XWindowAttributes = std::mem::zeroed();
+            if (self.xlib.XGetWindowAttributes)(self.ptr, window, &mut attributes) == 0 {
+                return None;
+            }
+
+            let (mut x, mut y, mut child) = (0, 0, 0);
+            (self.xlib.XTranslateCoordinates)(
+                self.ptr, window, self.root, 0, 0, &mut x, &mut y, &mut child,
+            );
+            Some(Geometry {
+                x,
+                y,
+                width: attributes.width as c_uint,
+                height: attributes.height as c_uint,
+            })
+        }
+    }
+
+    pub fn is_viewable(&self, window: Window) -> bool {
+        unsafe {
+            let mut attributes: xlib::XWindowAttributes = std::mem::zeroed();
+            (self.xlib.XGetWindowAttributes)(self.ptr, window, &mut attributes) != 0
+                && attributes.map_state == xlib::IsViewable
+        }
+    }
+
+    pub fn map(&self, window: Window) {
+        unsafe {
+            (self.xlib.XMapRaised)(self.ptr, window);
+        }
+        self.flush();
+    }
+
+    /// Unmaps `window` and tells the window manager to stop managing it.
+    pub fn withdraw(&self, window: Window) {
+        unsafe {
+            let screen = (self.xlib.XDefaultScreen)(self.ptr);
+            (self.xlib.XWithdrawWindow)(self.ptr, window, screen);
+        }
+        self.flush();
+    }
+
+    /// Direct children of the root window, in stacking order from bottom to top.
+    pub fn top_level_windows(&self) -> Vec<Window> {
+        unsafe {
+            let (mut root, mut parent) = (0, 0);
+            let mut children = std::ptr::null_mut();
+            let mut count = 0;
+
+            if (self.xlib.XQueryTree)(
+                self.ptr,
+                self.root,
+                &mut root,
+                &mut parent,
+                &mut children,
+                &mut count,
+            ) == 0
+                || children.is_null()
+            {
+                return vec![];
+            }
+            let windows = std::slice::from_raw_parts(children, count as usize).to_vec();
+            (self.xlib.XFree)(children as *mut _);
+            windows
+        }
+    }
+
+    /// The window holding the keyboard focus.
+    pub fn input_focus(&self) -> Option<Window> {
+        let mut window = 0;
+        let mut revert_to = 0;
+        unsafe {
+            (self.xlib.XGetInputFocus)(self.ptr, &mut window, &mut revert_to);
+        }
+        // PointerRoot and None are not windows
+        (window > 1).then_some(window)
+    }
+
+    pub fn focus(&self, window: Window) {
+        unsafe {
+            (self.xlib.XSetInputFocus)(self.ptr, window, xlib::RevertToParent, xlib::CurrentTime);
+        }
+        self.flush();
+    }
+
+    pub fn raise(&self, window: Window) {
+        unsafe {
+            (self.xlib.XRaiseWindow)(self.ptr, window);
+        }
+        self.flush();
+    }
+
+    pub fn move_resize(&self, window: Window, geometry: Geometry) {
+        unsafe {
+            (self.xlib.XMoveResizeWindow)(
+                self.ptr,
+                window,
+                geometry.x,
+                geometry.y,
+                geometry.width,
+                geometry.height,
+            );
+        }
+        self.flush();
+    }
+
+    /// Sends a client message about `window` to the root window, the way EWMH expects requests
+    /// to the window manager.
+    pub fn send_client_message(&self, window: Window, message_type: &str, data: [c_long; 5]) {
+        unsafe {
+            let mut event: xlib::XEvent = std::mem::zeroed();
+            event.client_message.type_ = xlib::ClientMessage;
+            event.client_message.window = window;
+            event.client_message.message_type = self.atom(message_type);
+            event.client_message.format = 32;
+            for (i, value) in data.into_iter().enumerate() {
+                event.client_message.data.set_long(i, value);
+            }
+
+            (self.xlib.XSendEvent)(
+                self.ptr,
+                self.root,
+                xlib::False,
+                xlib::SubstructureRedirectMask | xlib::SubstructureNotifyMask,
+                &mut event,
+            );
+        }
+        self.flush();
+    }
+
+    pub fn flush(&self) {
+        unsafe {
+            (self.xlib.XFlush)(self.ptr);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Geometry {
+    pub x: c_int,
+    pub y: c_int,
+    pub width: c_uint,
+    pub height: c_uint,
+}
+
+impl IntoLua for Geometry {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set("x", self.x)?;
+        table.set("y", self.y)?;
+        table.set("width", self.width)?;
+        table.set("height", self.height)?;
+        table.into_lua(lua)
+    }
+}
+
+impl Drop for Display {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xlib.XCloseDisplay)(self.ptr);
+        }
+    }
+}
+
+/// Splits a list of NUL separated strings. The NUL terminating the last string is not a
+/// separator, empty strings in between are kept so the positions stay right, e.g. for
+/// _NET_DESKTOP_NAMES.
+fn split_strings(bytes: &[u8]) -> Vec<String> {
+    let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+    if bytes.is_empty() {
+        return vec![];
+    }
+    bytes
+        .split(|b| *b == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_strings_keeps_empty_entries() {
+        assert_eq!(split_strings(b"navigator\0Firefox\0"), ["navigator", "Firefox"]);
+        assert_eq!(split_strings(b"one\0\0three\0"), ["one", "", "three"]);
+        assert_eq!(split_strings(b"\0two"), ["", "two"]);
+        assert_eq!(split_strings(b"title"), ["title"]);
+        assert!(split_strings(b"").is_empty());
+    }
+}
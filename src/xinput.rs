@@ -0,0 +1,103 @@
+use std::ffi::{c_int, c_uchar};
+
+use x11_dl::{xinput2, xlib};
+
+use crate::{logger::log, xconn::Display};
+
+/// A raw key or button event, reported no matter which window has the focus or grabbed the key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawEvent {
+    KeyPress(u8),
+    KeyRelease(u8),
+    ButtonPress,
+}
+
+/// The XInput2 extension, used to see keys that are grabbed or that aren't grabbed at all, like
+/// modifiers pressed on their own.
+pub struct XInput {
+    lib: xinput2::XInput2,
+    opcode: c_int,
+}
+
+impl XInput {
+    /// Loads libXi and checks that the server supports XInput 2.1, the first version reporting
+    /// raw events while keys are grabbed. Returns None and logs why if it doesn't.
+    pub fn open(display: &Display) -> Option<Self> {
+        let lib = match xinput2::XInput2::open() {
+            Ok(lib) => lib,
+            Err(e) => {
+                log(format!("Could not load XInput2: {e}"));
+                return None;
+            }
+        };
+
+        let (mut opcode, mut event, mut error) = (0, 0, 0);
+        let (mut major, mut minor) = (2, 1);
+        unsafe {
+            let name = c"XInputExtension";
+            if (display.xlib.XQueryExtension)(
+                display.ptr,
+                name.as_ptr(),
+                &mut opcode,
+                &mut event,
+                &mut error,
+            ) == 0
+                || (lib.XIQueryVersion)(display.ptr, &mut major, &mut minor)
+                    != xlib::Success as c_int
+            {
+                log("The X server does not support XInput 2.1");
+                return None;
+            }
+        }
+
+        Some(Self { lib, opcode })
+    }
+
+    /// Asks the server for raw key presses and releases and raw button presses of all devices.
+    pub fn select_raw_events(&self, display: &Display) {
+        let mut mask: [c_uchar; 4] = [0; 4];
+        for event in [
+            xinput2::XI_RawKeyPress,
+            xinput2::XI_RawKeyRelease,
+            xinput2::XI_RawButtonPress,
+        ] {
+            mask[event as usize / 8] |= 1 << (event % 8);
+        }
+
+        let mut event_mask = xinput2::XIEventMask {
+            deviceid: xinput2::XIAllMasterDevices,
+            mask_len: mask.len() as c_int,
+            mask: mask.as_mut_ptr(),
+        };
+        unsafe {
+            (self.lib.XISelectEvents)(display.ptr, display.root, &mut event_mask, 1);
+        }
+    }
+
+    /// The raw event `event` holds, if it is one. Key repeats are left out.
+    pub fn raw_event(&self, display: &Display, event: &mut xlib::XEvent) -> Option<RawEvent> {
+        if event.get_type() != xlib::GenericEvent {
+            return None;
+        }
+
+        unsafe {
+            let cookie = &mut event.generic_event_cookie;
+            if cookie.extension != self.opcode
+                || (display.xlib.XGetEventData)(display.ptr, cookie) == 0
+            {
+                return None;
+            }
+
+            let raw = &*(cookie.data as *const xinput2::XIRawEvent);
+            let repeat = raw.flags & xinput2::XIKeyRepeat != 0;
+            let raw_event = match cookie.evtype {
+                xinput2::XI_RawKeyPress if !repeat => Some(RawEvent::KeyPress(raw.detail as u8)),
+                xinput2::XI_RawKeyRelease => Some(RawEvent::KeyRelease(raw.detail as u8)),
+                xinput2::XI_RawButtonPress => Some(RawEvent::ButtonPress),
+                _ => None,
+            };
+            (display.xlib.XFreeEventData)(display.ptr, cookie);
+            raw_event
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, c_char, c_int, c_uint, c_ulong},
+};
+
+use x11_dl::xlib;
+
+use crate::xconn::Display;
+
+/// Device id of the core keyboard, `XkbUseCoreKbd` is missing from x11-dl.
+const USE_CORE_KBD: c_uint = 0x0100;
+
+/// XKB allows up to four groups (layouts) per keyboard.
+pub const GROUPS: [u8; 4] = [0, 1, 2, 3];
+
+/// The plain and the shifted level of a key.
+const LEVELS: [u8; 2] = [0, 1];
+
+/// Returns the first event code of the XKB extension, or None if the server doesn't support it.
+pub fn event_base(display: &Display) -> Option<c_int> {
+    let (mut opcode, mut event, mut error) = (0, 0, 0);
+    // XKB 1.0, XkbMajorVersion and XkbMinorVersion are missing from x11-dl as well
+    let (mut major, mut minor) = (1, 0);
+
+    unsafe {
+        ((display.xlib.XkbQueryExtension)(
+            display.ptr,
+            &mut opcode,
+            &mut event,
+            &mut error,
+            &mut major,
+            &mut minor,
+        ) != 0)
+            .then_some(event)
+    }
+}
+
+/// Asks the server to report layout (group) switches and keyboards being replaced, e.g. by
+/// plugging in another keyboard.
+pub fn select_events(display: &Display) {
+    unsafe {
+        (display.xlib.XkbSelectEvents)(
+            display.ptr,
+            USE_CORE_KBD,
+            xlib::XkbNewKeyboardNotifyMask,
+            xlib::XkbNewKeyboardNotifyMask,
+        );
+        (display.xlib.XkbSelectEventDetails)(
+            display.ptr,
+            USE_CORE_KBD,
+            xlib::XkbStateNotify as c_uint,
+            xlib::XkbGroupStateMask,
+            xlib::XkbGroupStateMask,
+        );
+    }
+}
+
+/// The active group, i.e. the index of the current layout.
+pub fn current_group(display: &Display) -> u8 {
+    unsafe {
+        let mut state: xlib::XkbStateRec = std::mem::zeroed();
+        (display.xlib.XkbGetState)(display.ptr, USE_CORE_KBD, &mut state);
+        state.group
+    }
+}
+
+/// The modifiers that are active, including locked ones like Caps Lock.
+pub fn current_mods(display: &Display) -> u32 {
+    unsafe {
+        let mut state: xlib::XkbStateRec = std::mem::zeroed();
+        (display.xlib.XkbGetState)(display.ptr, USE_CORE_KBD, &mut state);
+        u32::from(state.mods)
+    }
+}
+
+/// The X name of `sym`, like "Return" or "a".
+pub fn keysym_name(display: &Display, sym: c_ulong) -> Option<String> {
+    unsafe {
+        let name = (display.xlib.XKeysymToString)(sym);
+        (!name.is_null()).then(|| CStr::from_ptr(name).to_string_lossy().into_owned())
+    }
+}
+
+/// The XKB event type of `event`, if it is an XKB event.
+fn xkb_type(event: &xlib::XEvent, event_base: c_int) -> Option<c_int> {
+    if event.get_type() != event_base {
+        return None;
+    }
+    let any = unsafe { &*(event as *const xlib::XEvent as *const xlib::XkbAnyEvent) };
+    Some(any.xkb_type)
+}
+
+/// Whether `event` reports that the keyboard was replaced.
+pub fn is_new_keyboard(event: &xlib::XEvent, event_base: c_int) -> bool {
+    xkb_type(event, event_base) == Some(xlib::XkbNewKeyboardNotify)
+}
+
+/// The new group if `event` reports a layout switch.
+pub fn group_change(event: &xlib::XEvent, event_base: c_int) -> Option<u8> {
+    if xkb_type(event, event_base)? != xlib::XkbStateNotify {
+        return None;
+    }
+
+    let state = unsafe { &*(event as *const xlib::XEvent as *const xlib::XkbStateNotifyEvent) };
+    (state.changed as c_ulong & xlib::XkbGroupStateMask != 0).then_some(state.group as u8)
+}
+
+/// The keysym `keycode` produces in `group` at shift `level`. Keys with fewer groups than the
+/// keyboard, e.g. function keys, fall back to their first group like XKB does.
+pub fn keysym(display: &Display, keycode: u8, group: u8, level: c_int) -> c_ulong {
+    let lookup = |group: u8, level: c_int| unsafe {
+        (display.xlib.XkbKeycodeToKeysym)(display.ptr, keycode, group as c_int, level)
+    };
+
+    match lookup(group, level) {
+        0 if group > 0 && lookup(group, 0) == 0 => lookup(0, level),
+        sym => sym,
+    }
+}
+
+/// Returns every key producing `sym` in any of `groups` together with the shift level it is on.
+/// A keysym is often found on several keys, e.g. a remapped Caps Lock or a numpad.
+pub fn keycodes(display: &Display, sym: c_ulong, groups: &[u8]) -> Vec<(u8, u8)> {
+    let (mut min, mut max) = (0, 0);
+    unsafe {
+        (display.xlib.XDisplayKeycodes)(display.ptr, &mut min, &mut max);
+    }
+
+    let mut keycodes = groups
+        .iter()
+        .flat_map(|group| {
+            (min..=max).map(|keycode| keycode as u8).filter_map(move |keycode| {
+                LEVELS
+                    .into_iter()
+                    .find(|level| keysym(display, keycode, *group, *level as c_int) == sym)
+                    .map(|level| (keycode, level))
+            })
+        })
+        .collect::<Vec<_>>();
+    keycodes.sort();
+    keycodes.dedup();
+    keycodes
+}
+
+/// Maps the keysym of every modifier key to the modifier mask it sets, e.g. Super_L to Mod4.
+/// Which of Mod1 to Mod5 the Alt, Super, Hyper and AltGr keys set differs between setups.
+pub fn modifier_masks(display: &Display) -> HashMap<c_ulong, u32> {
+    let mut masks = HashMap::new();
+    unsafe {
+        let map = (display.xlib.XGetModifierMapping)(display.ptr);
+        if map.is_null() {
+            return masks;
+        }
+
+        let per_modifier = (*map).max_keypermod as usize;
+        let keycodes = std::slice::from_raw_parts((*map).modifiermap, 8 * per_modifier);
+        for (index, keycodes) in keycodes.chunks(per_modifier.max(1)).enumerate() {
+            for keycode in keycodes.iter().filter(|keycode| **keycode != 0) {
+                let sym = (display.xlib.XkbKeycodeToKeysym)(display.ptr, *keycode, 0, 0);
+                masks.entry(sym).or_insert(1 << index);
+            }
+        }
+        (display.xlib.XFreeModifiermap)(map);
+    }
+    masks
+}
+
+/// Whether a key producing `sym` in the first group is held down right now.
+pub fn is_pressed(display: &Display, sym: c_ulong) -> bool {
+    let mut keys: [c_char; 32] = [0; 32];
+    unsafe {
+        (display.xlib.XQueryKeymap)(display.ptr, keys.as_mut_ptr());
+    }
+
+    keycodes(display, sym, &[0])
+        .into_iter()
+        .any(|(keycode, _)| keys[keycode as usize / 8] as u8 & (1 << (keycode % 8)) != 0)
+}